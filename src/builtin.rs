@@ -1,7 +1,46 @@
-use std::{cmp::Ordering, env, fmt, fs, path::PathBuf, str::FromStr, vec};
+use std::{
+    cmp::Ordering,
+    env, fmt, fs,
+    path::PathBuf,
+    str::FromStr,
+    sync::atomic::{self, AtomicUsize},
+    vec,
+};
 
 use prettytable::{Cell, Row};
-use rlua::{Lua, MetaMethod, ToLua, UserData};
+use rlua::{MetaMethod, ToLua, UserData};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Width of the terminal tables are rendered for, 0 if unknown.
+static TABLE_WIDTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Called on startup and whenever the terminal is resized.
+pub fn set_table_width(width: usize) {
+    TABLE_WIDTH.store(width, atomic::Ordering::Relaxed);
+}
+
+/// The lines of `cell` cut to `width` columns, with an ellipsis where
+/// something was left out.
+fn truncate(cell: &str, width: usize) -> String {
+    cell.split('\n')
+        .map(|line| {
+            if line.width() <= width {
+                return line.to_string();
+            }
+            let mut res = String::new();
+            for g in line.graphemes(true) {
+                if res.width() + g.width() + 1 > width {
+                    break;
+                }
+                res.push_str(g);
+            }
+            res.push('…');
+            res
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
 
 #[derive(Debug, Default, Clone)]
 pub struct TableRes {
@@ -9,21 +48,83 @@ pub struct TableRes {
     pub entries: Vec<Vec<String>>,
 }
 impl TableRes {
+    /// Widest line of each column, after shrinking the widest ones until the
+    /// table fits in the terminal.
+    fn column_widths(&self) -> Vec<usize> {
+        let lines = || std::iter::once(&self.header).chain(self.entries.iter());
+        let mut widths = vec![0; lines().map(Vec::len).max().unwrap_or(0)];
+        for line in lines() {
+            for (width, cell) in widths.iter_mut().zip(line) {
+                *width = cell.split('\n').map(|l| l.width()).fold(*width, usize::max);
+            }
+        }
+
+        let available = match TABLE_WIDTH.load(atomic::Ordering::Relaxed) {
+            0 => return widths,
+            // One space of padding on each side of every cell
+            width => width.saturating_sub(2 * widths.len()),
+        };
+        while widths.iter().sum::<usize>() > available {
+            match widths.iter_mut().max() {
+                Some(widest) if *widest > 1 => *widest -= 1,
+                _ => break,
+            }
+        }
+        widths
+    }
+
     pub fn as_display_table(&self) -> prettytable::Table {
+        let widths = self.column_widths();
+
         let mut table = prettytable::Table::new();
         table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
         table.set_titles(Row::new(
             self.header
                 .iter()
-                .map(|v| Cell::new(v).style_spec("biuc"))
+                .zip(&widths)
+                .map(|(v, w)| Cell::new(&truncate(v, *w)).style_spec("biuc"))
                 .collect(),
         ));
         for entry in &self.entries {
-            table.add_row(Row::new(entry.iter().map(|v| Cell::new(v)).collect()));
+            table.add_row(Row::new(
+                entry
+                    .iter()
+                    .zip(&widths)
+                    .map(|(v, w)| Cell::new(&truncate(v, *w)))
+                    .collect(),
+            ));
         }
 
         table
     }
+
+    /// Header and rows as tab-separated lines, for tools like `sort` or `cut`.
+    pub fn to_tsv(&self) -> String {
+        let mut res = String::new();
+        for line in std::iter::once(&self.header).chain(self.entries.iter()) {
+            res.push_str(&line.join("\t"));
+            res.push('\n');
+        }
+        res
+    }
+
+    /// Rows as a JSON array of objects keyed by the header, for tools like `jq`.
+    pub fn to_json(&self) -> String {
+        let rows = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let fields = self
+                    .header
+                    .iter()
+                    .zip(entry.iter())
+                    .map(|(k, v)| format!("{}:{}", json_string(k), json_string(v)))
+                    .collect::<Vec<_>>();
+                format!("{{{}}}", fields.join(","))
+            })
+            .collect::<Vec<_>>();
+        format!("[{}]", rows.join(","))
+    }
 }
 impl fmt::Display for TableRes {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -85,6 +186,24 @@ impl UserData for TableRes {
     }
 }
 
+pub fn json_string(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
 pub fn ls(dir: &str) -> TableRes {
     let mut entries = fs::read_dir(if dir.is_empty() { "." } else { dir })
         .unwrap()
@@ -126,4 +245,51 @@ pub fn cd(dir: &str) {
     }
 }
 
-//pub fn pipe(lua: &Lua, )
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(header: &[&str], entries: &[&[&str]]) -> TableRes {
+        TableRes {
+            header: header.iter().map(|s| s.to_string()).collect(),
+            entries: entries
+                .iter()
+                .map(|entry| entry.iter().map(|s| s.to_string()).collect())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_string(""), "\"\"");
+        assert_eq!(json_string("say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(json_string("C:\\dir"), "\"C:\\\\dir\"");
+        assert_eq!(json_string("a\nb\r\tc"), "\"a\\nb\\r\\tc\"");
+        assert_eq!(json_string("\x01\x1f"), "\"\\u0001\\u001f\"");
+        assert_eq!(json_string("é ✓ /tmp/my dir"), "\"é ✓ /tmp/my dir\"");
+    }
+
+    #[test]
+    fn tsv() {
+        let t = table(
+            &["name", "type"],
+            &[&["my file.txt", "file"], &["a dir", "dir"]],
+        );
+        assert_eq!(t.to_tsv(), "name\ttype\nmy file.txt\tfile\na dir\tdir\n");
+        assert_eq!(table(&["name"], &[]).to_tsv(), "name\n");
+    }
+
+    #[test]
+    fn json() {
+        let t = table(
+            &["name", "note"],
+            &[&["my \"file\"", "a\\b"], &["x", "line\nbreak"]],
+        );
+        assert_eq!(
+            t.to_json(),
+            "[{\"name\":\"my \\\"file\\\"\",\"note\":\"a\\\\b\"},\
+             {\"name\":\"x\",\"note\":\"line\\nbreak\"}]"
+        );
+        assert_eq!(table(&["name"], &[]).to_json(), "[]");
+    }
+}
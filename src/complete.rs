@@ -0,0 +1,520 @@
+use std::{collections::BTreeSet, fs, mem, path::PathBuf};
+
+use rlua::Context;
+
+use crate::exec;
+
+/// Candidates for the word ending at the cursor.
+pub struct Completion {
+    /// Byte offset in the cursor's line where the completed word starts
+    pub start: usize,
+    pub candidates: Vec<String>,
+}
+
+/// Registry table of the completion providers, by command name.
+const COMPLETIONS_KEY: &str = "__completions";
+
+#[derive(Debug, PartialEq)]
+enum Place {
+    Code,
+    String,
+    Comment,
+}
+
+pub const KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn is_identifier(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars().all(is_ident_char)
+        && !s.starts_with(|c: char| c.is_ascii_digit())
+        && !KEYWORDS.contains(&s)
+}
+
+/// Byte offset where the run of characters matching `f` at the end of `s`
+/// starts.
+fn run_start(s: &str, f: impl Fn(char) -> bool) -> usize {
+    s.char_indices()
+        .rev()
+        .find(|(_, c)| !f(*c))
+        .map_or(0, |(i, c)| i + c.len_utf8())
+}
+
+/// Where `offset` is in `code`, according to tree-sitter.
+fn place(code: &str, offset: usize, line: &str) -> Place {
+    let mut parser = tree_sitter::Parser::new();
+    let tree = match parser
+        .set_language(tree_sitter_lua::language())
+        .ok()
+        .and_then(|_| parser.parse(code, None))
+    {
+        Some(tree) => tree,
+        None => return place_in_line(line),
+    };
+
+    let mut node = tree
+        .root_node()
+        .descendant_for_byte_range(offset.saturating_sub(1), offset);
+    while let Some(n) = node {
+        match n.kind() {
+            "string" => {
+                let unterminated = n.child_by_field_name("end").is_some_and(|e| e.is_missing());
+                if n.start_byte() < offset && (offset < n.end_byte() || unterminated) {
+                    return Place::String;
+                }
+                return Place::Code;
+            }
+            "comment" => return Place::Comment,
+            _ => node = n.parent(),
+        }
+    }
+
+    // An unterminated string usually ends up in an ERROR node instead
+    match tree.root_node().has_error() {
+        true => place_in_line(line),
+        false => Place::Code,
+    }
+}
+
+/// Where the end of `line` is, looking only at quotes and comments.
+fn place_in_line(line: &str) -> Place {
+    let mut quote = None;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(_), '\\') => {
+                chars.next();
+            }
+            (Some(q), c) if c == q => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '-') if chars.peek() == Some(&'-') => return Place::Comment,
+            _ => {}
+        }
+    }
+
+    match quote {
+        Some(_) => Place::String,
+        None => Place::Code,
+    }
+}
+
+/// Names an executable can be reached with from Lua.
+fn executable_names(lua_ctx: Context) -> rlua::Result<Vec<String>> {
+    Ok(exec::executable_names(lua_ctx)?
+        .into_iter()
+        .map(|name| match is_identifier(&name) {
+            true => name,
+            false => exec::mangle(&name),
+        })
+        .collect())
+}
+
+fn table_keys(table: rlua::Table, methods: bool) -> Vec<String> {
+    table
+        .pairs::<rlua::Value, rlua::Value>()
+        .filter_map(|pair| pair.ok())
+        .filter_map(|(key, value)| match (key, value) {
+            (rlua::Value::String(key), rlua::Value::Function(_)) => {
+                key.to_str().ok().map(String::from)
+            }
+            (rlua::Value::String(key), _) if !methods => key.to_str().ok().map(String::from),
+            _ => None,
+        })
+        .filter(|key| is_identifier(key))
+        .collect()
+}
+
+/// Globals and executables, or the fields of the table reached through the
+/// `.` separated `path`. Only functions after `:`.
+fn names(lua_ctx: Context, path: &str, methods: bool) -> rlua::Result<Vec<String>> {
+    let globals = lua_ctx.globals();
+    if path.is_empty() {
+        let mut res = table_keys(globals, false);
+        res.extend(executable_names(lua_ctx)?);
+        return Ok(res);
+    }
+
+    let mut value = rlua::Value::Table(globals);
+    for part in path.split('.') {
+        value = match value {
+            // Completion runs while typing, so `__index` metamethods are
+            // left alone
+            rlua::Value::Table(table) if is_identifier(part) => table.raw_get(part)?,
+            _ => return Ok(vec![]),
+        };
+    }
+
+    Ok(match value {
+        rlua::Value::Table(table) => {
+            let mut res = table_keys(table, methods);
+            // Commands are only resolved on access
+            if path == "cmd" {
+                res.extend(executable_names(lua_ctx)?);
+            }
+            res
+        }
+        _ => vec![],
+    })
+}
+
+/// Entries of the directory in `word`, a path typed so far.
+fn files(word: &str) -> Vec<String> {
+    let (dir, base) = match word.rfind('/') {
+        Some(i) => (&word[..=i], &word[i + 1..]),
+        None => ("", word),
+    };
+    let path = match dir.strip_prefix("~/") {
+        Some(rest) => home::home_dir().map(|home| home.join(rest)),
+        None if dir.is_empty() => Some(PathBuf::from(".")),
+        None => Some(PathBuf::from(dir)),
+    };
+
+    path.and_then(|path| fs::read_dir(path).ok())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let mut name = entry.file_name().to_str()?.to_string();
+            if name.starts_with('.') && !base.starts_with('.') {
+                return None;
+            }
+            if entry.path().is_dir() {
+                name.push('/');
+            }
+            Some(name)
+        })
+        .collect()
+}
+
+/// Register `f` to complete the arguments of calls to `name`, or remove the
+/// provider when `None`.
+pub fn register<'lua>(
+    lua_ctx: Context<'lua>,
+    name: String,
+    f: Option<rlua::Function<'lua>>,
+) -> rlua::Result<()> {
+    let providers = match lua_ctx.named_registry_value::<_, Option<rlua::Table>>(COMPLETIONS_KEY)? {
+        Some(providers) => providers,
+        None => {
+            let providers = lua_ctx.create_table()?;
+            lua_ctx.set_named_registry_value(COMPLETIONS_KEY, providers.clone())?;
+            providers
+        }
+    };
+    providers.set(name, f)
+}
+
+/// The provider for `name`, also found through its mangled form.
+fn provider<'lua>(
+    lua_ctx: Context<'lua>,
+    name: &str,
+) -> rlua::Result<Option<rlua::Function<'lua>>> {
+    let providers = match lua_ctx.named_registry_value::<_, Option<rlua::Table>>(COMPLETIONS_KEY)? {
+        Some(providers) => providers,
+        None => return Ok(None),
+    };
+    if let Some(f) = providers.get(name)? {
+        return Ok(Some(f));
+    }
+    Ok(providers
+        .pairs::<String, rlua::Function>()
+        .filter_map(|pair| pair.ok())
+        .find(|(key, _)| exec::mangle(key) == name)
+        .map(|(_, f)| f))
+}
+
+enum Token {
+    /// An identifier, possibly indexed with `.` or `:`
+    Name(String),
+    Str(String),
+    Open(char),
+    Close,
+    Comma,
+    Other(String),
+}
+
+/// Rough tokens of `code`, enough to find the call around the cursor.
+fn tokens(code: &str) -> Vec<Token> {
+    let mut res = vec![];
+    let mut chars = code.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '-' if chars.peek() == Some(&'-') => {
+                chars.by_ref().find(|c| *c == '\n');
+            }
+            '"' | '\'' => {
+                let mut content = String::new();
+                while let Some(next) = chars.next() {
+                    match next {
+                        '\\' => content.extend(chars.next()),
+                        next if next == c => break,
+                        next => content.push(next),
+                    }
+                }
+                res.push(Token::Str(content));
+            }
+            c if is_ident_char(c) => {
+                let mut name = c.to_string();
+                while let Some(next) =
+                    chars.next_if(|c| is_ident_char(*c) || *c == '.' || *c == ':')
+                {
+                    name.push(next);
+                }
+                match c.is_ascii_digit() {
+                    true => res.push(Token::Other(name)),
+                    false => res.push(Token::Name(name)),
+                }
+            }
+            '(' | '{' | '[' => res.push(Token::Open(c)),
+            ')' | '}' | ']' => res.push(Token::Close),
+            ',' => res.push(Token::Comma),
+            c => res.push(Token::Other(c.to_string())),
+        }
+    }
+
+    res
+}
+
+/// A function call whose argument list contains the end of the code.
+struct Call {
+    name: String,
+    /// Arguments before the current one, with quotes removed from strings
+    args: Vec<String>,
+    /// The current argument so far
+    current: String,
+}
+
+fn call_at(code: &str) -> Option<Call> {
+    struct Frame {
+        name: Option<String>,
+        args: Vec<String>,
+        current: String,
+    }
+
+    let tokens = tokens(code);
+    let mut frames: Vec<Frame> = vec![];
+    let mut previous: Option<&Token> = None;
+
+    for token in &tokens {
+        match token {
+            Token::Open(c) => frames.push(Frame {
+                name: match (c, previous) {
+                    ('(', Some(Token::Name(name))) => Some(name.clone()),
+                    _ => None,
+                },
+                args: vec![],
+                current: String::new(),
+            }),
+            Token::Close => {
+                frames.pop();
+            }
+            Token::Comma => {
+                if let Some(frame) = frames.last_mut() {
+                    frame.args.push(mem::take(&mut frame.current));
+                }
+            }
+            Token::Str(content) => {
+                if let Some(frame) = frames.last_mut() {
+                    frame.current = content.clone();
+                }
+            }
+            Token::Name(text) | Token::Other(text) => {
+                if let Some(frame) = frames.last_mut() {
+                    frame.current.push_str(text);
+                }
+            }
+        }
+        previous = Some(token);
+    }
+
+    let (name, args, current) = match tokens.as_slice() {
+        // `name "string"` call syntax
+        [.., Token::Name(name), Token::Str(current)] => (name.clone(), vec![], current.clone()),
+        _ => {
+            let frame = frames.pop()?;
+            (frame.name?, frame.args, frame.current)
+        }
+    };
+
+    // Methods aren't commands
+    if name.contains(':') {
+        return None;
+    }
+    Some(Call {
+        name: name.rsplit('.').next().unwrap_or(&name).to_string(),
+        args,
+        current,
+    })
+}
+
+/// Ask the provider registered for the call around the cursor, if the
+/// current argument is the word being completed. Candidates are quoted when
+/// completing outside of a string.
+fn user_completion(
+    lua_ctx: Context,
+    code: &str,
+    line: &str,
+    in_string: bool,
+) -> rlua::Result<Option<Completion>> {
+    let call = match call_at(code) {
+        Some(call) => call,
+        None => return Ok(None),
+    };
+    let word = match in_string {
+        true => &line[line.rfind(['"', '\'']).map_or(0, |i| i + 1)..],
+        false => &line[run_start(line, is_ident_char)..],
+    };
+    if call.current != word {
+        return Ok(None);
+    }
+    let f = match provider(lua_ctx, &call.name)? {
+        Some(f) => f,
+        None => return Ok(None),
+    };
+
+    let candidates = match f.call::<_, Option<Vec<String>>>((call.args, word))? {
+        Some(candidates) => candidates,
+        None => return Ok(None),
+    };
+    let candidates = candidates
+        .into_iter()
+        .filter(|candidate| candidate.starts_with(word))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|candidate| match in_string {
+            true => candidate,
+            false => format!(
+                "\"{}\"",
+                candidate.replace('\\', "\\\\").replace('"', "\\\"")
+            ),
+        })
+        .collect();
+
+    Ok(Some(Completion {
+        start: line.len() - word.len(),
+        candidates,
+    }))
+}
+
+/// Complete the word before byte `offset` of `code`: with the provider of the
+/// surrounding call if there is one, then a file name inside a string, and a
+/// global, a command or a table field otherwise.
+pub fn complete(lua_ctx: Context, code: &str, offset: usize) -> rlua::Result<Option<Completion>> {
+    let line_start = code[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = &code[line_start..offset];
+
+    let place = place(code, offset, line);
+    if place != Place::Comment {
+        let completion = user_completion(lua_ctx, &code[..offset], line, place == Place::String)?;
+        if completion.is_some() {
+            return Ok(completion);
+        }
+    }
+
+    let (start, candidates) = match place {
+        Place::Comment => return Ok(None),
+        Place::String => {
+            let word_start = line.rfind(['"', '\'']).map_or(0, |i| i + 1);
+            let word = &line[word_start..];
+            let start = word.rfind('/').map_or(word_start, |i| word_start + i + 1);
+            (start, files(word))
+        }
+        Place::Code => {
+            let start = run_start(line, is_ident_char);
+            if line[start..].starts_with(|c: char| c.is_ascii_digit()) {
+                return Ok(None);
+            }
+
+            let before = &line[..start];
+            let (path, methods) = match before.strip_suffix(['.', ':']) {
+                Some(rest) => {
+                    let path_start = run_start(rest, |c| is_ident_char(c) || c == '.');
+                    let path = &rest[path_start..];
+                    if path.is_empty() {
+                        return Ok(None);
+                    }
+                    (path, before.ends_with(':'))
+                }
+                None => ("", false),
+            };
+            (start, names(lua_ctx, path, methods)?)
+        }
+    };
+
+    let word = &line[start..];
+    let candidates = candidates
+        .into_iter()
+        .filter(|candidate| candidate.starts_with(word))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+
+    Ok(Some(Completion { start, candidates }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn places_in_line() {
+        assert_eq!(place_in_line("ls("), Place::Code);
+        assert_eq!(place_in_line("cd(\"/tmp/my dir/"), Place::String);
+        assert_eq!(place_in_line("x = 'it\\'s "), Place::String);
+        assert_eq!(place_in_line("x = \"a\\\\\" "), Place::Code);
+        assert_eq!(place_in_line("print(\"--\") "), Place::Code);
+        assert_eq!(place_in_line("print('a') -- b"), Place::Comment);
+        assert_eq!(place_in_line("\"a\" .. '"), Place::String);
+    }
+
+    #[test]
+    fn strings_are_unquoted() {
+        let tokens = tokens("f(\"a \\\"b\\\"\", 'c, d') -- g(\"x\"");
+        let strings = tokens
+            .iter()
+            .filter_map(|token| match token {
+                Token::Str(s) => Some(s.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(strings, ["a \"b\"", "c, d"]);
+        assert!(matches!(tokens.last(), Some(Token::Close)));
+        assert!(matches!(&tokens[0], Token::Name(name) if name == "f"));
+    }
+
+    #[test]
+    fn calls() {
+        let call = call_at("cd(\"/tmp/my dir/sub").unwrap();
+        assert_eq!(call.name, "cd");
+        assert!(call.args.is_empty());
+        assert_eq!(call.current, "/tmp/my dir/sub");
+
+        let call = call_at("git(\"commit\", \"-m\", \"a, (b").unwrap();
+        assert_eq!(call.name, "git");
+        assert_eq!(call.args, ["commit", "-m"]);
+        assert_eq!(call.current, "a, (b");
+
+        // The innermost call still open
+        let call = call_at("ls(cat(\"x\"), git(\"log\"), \"-").unwrap();
+        assert_eq!(call.name, "ls");
+        assert_eq!(call.args.len(), 2);
+        assert_eq!(call.current, "-");
+
+        let call = call_at("cmd.git_lfs \"pu").unwrap();
+        assert_eq!(call.name, "git_lfs");
+        assert_eq!(call.current, "pu");
+
+        assert!(call_at("x:method(\"a").is_none());
+        assert!(call_at("ls(\"a\")").is_none());
+        assert!(call_at("{1, ").is_none());
+    }
+}
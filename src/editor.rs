@@ -0,0 +1,1206 @@
+use std::{
+    io::{stdout, Write},
+    sync::Mutex,
+};
+
+use crossterm::{
+    cursor::{position, MoveTo, Show},
+    event::{KeyCode, KeyModifiers},
+    queue,
+    style::{Attribute, Print, Stylize},
+    terminal::{size, Clear, ClearType, ScrollUp},
+};
+use rlua::Lua;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use crate::{complete, error_message, highlight, history, jobs, print, vi, BoxedRes};
+
+/// Ctrl-R mode: the buffer shows the selected history entry matching `query`.
+struct Search {
+    query: String,
+    matches: Vec<String>,
+    selected: usize,
+    /// The buffer and cursor from before the search, restored on cancel.
+    draft: (Vec<String>, (usize, usize)),
+}
+impl Search {
+    fn prompt(&self) -> String {
+        match self.matches.len() {
+            0 => format!("(no match) search: {}", self.query),
+            n => format!("[{}/{n}] search: {}", self.selected + 1, self.query),
+        }
+    }
+}
+
+/// Byte offset of the grapheme cluster before byte `i` of `line`.
+pub fn previous_grapheme(line: &str, i: usize) -> usize {
+    line[..i]
+        .grapheme_indices(true)
+        .next_back()
+        .map_or(0, |(j, _)| j)
+}
+
+/// Byte offset of the grapheme cluster after the one at byte `i` of `line`.
+pub fn next_grapheme(line: &str, i: usize) -> usize {
+    line[i..].graphemes(true).next().map_or(i, |g| i + g.len())
+}
+
+pub fn is_word(g: &str) -> bool {
+    g.starts_with(|c: char| c.is_alphanumeric() || c == '_')
+}
+
+/// Whether the code before a line break opens a block, whose body is then
+/// indented.
+fn opens_block(before: &str) -> bool {
+    let before = before.trim_end();
+    // Whole words only, `todo` doesn't open a block
+    let words = || before.split(|c: char| !c.is_alphanumeric() && c != '_');
+    ["then", "do", "else", "repeat"].contains(&words().next_back().unwrap_or(""))
+        || before.ends_with(['{', '(', '['])
+        || (words().any(|word| word == "function") && before.ends_with(')'))
+}
+
+/// Start of the word before byte `i` of `code`, skipping what separates them.
+/// With `is_word` being `|g| !g.trim().is_empty()`, words are everything
+/// between whitespace.
+fn word_start(code: &str, i: usize, is_word: fn(&str) -> bool) -> usize {
+    let mut graphemes = code[..i].grapheme_indices(true).rev().peekable();
+    while graphemes.next_if(|(_, g)| !is_word(g)).is_some() {}
+    while graphemes.next_if(|(_, g)| is_word(g)).is_some() {}
+    graphemes.next().map_or(0, |(j, g)| j + g.len())
+}
+
+/// End of the word after byte `i` of `code`, skipping what separates them.
+fn word_end(code: &str, i: usize) -> usize {
+    let mut graphemes = code[i..].grapheme_indices(true).peekable();
+    while graphemes.next_if(|(_, g)| !is_word(g)).is_some() {}
+    while graphemes.next_if(|(_, g)| is_word(g)).is_some() {}
+    graphemes.next().map_or(code.len(), |(j, _)| i + j)
+}
+
+/// Byte offset of the grapheme cluster displayed at `column` in `line`, or
+/// its end when shorter.
+pub fn grapheme_at_column(line: &str, column: usize) -> usize {
+    let mut width = 0;
+    for (i, g) in line.grapheme_indices(true) {
+        width += g.width();
+        if width > column {
+            return i;
+        }
+    }
+    line.len()
+}
+
+/// `line` cut after `width` columns, so that it doesn't wrap. Escape codes
+/// are kept but take no room.
+fn clip(line: &str, width: usize) -> String {
+    let mut res = String::new();
+    let mut used = 0;
+    let mut full = false;
+    let mut graphemes = line.graphemes(true);
+    while let Some(g) = graphemes.next() {
+        if g == "\x1b" {
+            res.push_str(g);
+            for g in graphemes.by_ref() {
+                res.push_str(g);
+                if g.chars().all(|c| c.is_ascii_alphabetic()) {
+                    break;
+                }
+            }
+        } else if !full && used + g.width() <= width {
+            used += g.width();
+            res.push_str(g);
+        } else {
+            // A narrower grapheme after a wide one that didn't fit is left
+            // out too
+            full = true;
+        }
+    }
+    res
+}
+
+/// Number of kills Ctrl-Y and Alt-Y can go back to.
+const KILL_RING_SIZE: usize = 32;
+
+/// Text removed by Ctrl-K, Ctrl-U, Ctrl-W and Alt-D, most recent last. Kept
+/// from one prompt to the next.
+static KILL_RING: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub fn push_kill(text: String) {
+    let mut ring = KILL_RING.lock().unwrap();
+    if ring.len() == KILL_RING_SIZE {
+        ring.remove(0);
+    }
+    ring.push(text);
+}
+
+/// Added on new lines inside a block.
+const INDENT: &str = "    ";
+
+/// Number of candidates shown at once by the completion menu.
+const MENU_HEIGHT: usize = 8;
+
+/// Tab completion menu, for the word starting at `start` on the cursor's line.
+struct Menu {
+    start: usize,
+    /// The word as it was before cycling through the candidates
+    word: String,
+    candidates: Vec<String>,
+    selected: Option<usize>,
+}
+impl Menu {
+    fn lines(&self) -> Vec<String> {
+        let selected = self.selected.unwrap_or(0);
+        let first = (selected + 1).saturating_sub(MENU_HEIGHT);
+        let mut res: Vec<String> = self
+            .candidates
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(MENU_HEIGHT)
+            .map(|(i, candidate)| match self.selected == Some(i) {
+                true => candidate.clone().reverse().to_string(),
+                false => candidate.clone(),
+            })
+            .collect();
+        let hidden = self.candidates.len() - first - res.len();
+        if hidden > 0 {
+            res.push(format!("({hidden} more)"));
+        }
+        res
+    }
+}
+
+/// A screen row of the buffer: bytes `start..end` of the line `line`.
+struct Row {
+    line: usize,
+    start: usize,
+    end: usize,
+}
+
+fn prompt(lua: &Lua) -> String {
+    lua.context(|lua_ctx| {
+        let globals = lua_ctx.globals();
+        let config = globals.get::<_, rlua::Table>("config")?;
+        let ps1 = config.get::<_, rlua::Function>("ps1")?;
+        ps1.call::<_, String>(())
+    })
+    .unwrap()
+}
+
+/// The line editor: the code being typed at the prompt, drawn under it.
+pub struct Command {
+    cmd: Vec<String>,
+    cursor_initial: (u16, u16),
+    cursor: (usize, usize),
+    redraw: bool,
+    /// While walking through the history with Up/Down: the index of the entry
+    /// shown, and the buffer as it was typed.
+    history: Option<(usize, Vec<String>)>,
+    search: Option<Search>,
+    menu: Option<Menu>,
+    /// Shown under the buffer until the next key.
+    message: Option<String>,
+    highlighter: highlight::Highlighter,
+    /// Last line of the prompt, to draw it again after scrolling back up
+    ps1: String,
+    /// First row shown, when the buffer is taller than the screen
+    scroll: usize,
+    /// Screen rows between the prompt and the cursor, as last drawn
+    cursor_offset: u16,
+    prompt_hidden: bool,
+    /// Submitted or cancelled, only the buffer is left on screen
+    finished: bool,
+    /// How far back in the kill ring the text just before the cursor was
+    /// yanked from, for Alt-Y to replace it with an older kill.
+    yanked: Option<usize>,
+    /// Modal editing, when `config.edit_mode` is `"vi"`
+    vi: Option<vi::Vi>,
+    /// The prompt depends on something that changed, like the vi mode
+    prompt_stale: bool,
+}
+impl Command {
+    pub fn new(lua: &Lua) -> Self {
+        for notification in jobs::update() {
+            print(&format!("{notification}\n")).unwrap();
+        }
+
+        let vi = lua.context(|lua_ctx| {
+            let config = lua_ctx.globals().get::<_, rlua::Table>("config").ok()?;
+            config.get::<_, Option<String>>("edit_mode").ok()?
+        });
+        let vi = match vi.as_deref() {
+            Some("vi") => Some(vi::Vi::new()),
+            _ => {
+                vi::disable();
+                None
+            }
+        };
+
+        let ps1 = prompt(lua);
+        print(&ps1).unwrap();
+
+        Command {
+            cmd: vec![String::new()],
+            cursor_initial: position().unwrap(),
+            cursor: (0, 0),
+            redraw: true,
+            history: None,
+            search: None,
+            menu: None,
+            message: None,
+            highlighter: highlight::Highlighter::new(),
+            ps1: ps1.rsplit('\n').next().unwrap_or_default().to_string(),
+            scroll: 0,
+            cursor_offset: 0,
+            prompt_hidden: false,
+            finished: false,
+            yanked: None,
+            vi,
+            prompt_stale: false,
+        }
+    }
+
+    pub fn new_from(old: Self, lua: &Lua) -> Self {
+        let mut command = Command::new(lua);
+        command.cmd = old.cmd;
+        command.cursor = old.cursor;
+        command.redraw = true;
+        command
+    }
+
+    /// Split the buffer into rows of at most `width` columns, the first one
+    /// starting after the prompt.
+    fn rows(&self, width: usize) -> Vec<Row> {
+        let mut res = vec![];
+
+        for (l, line) in self.cmd.iter().enumerate() {
+            let mut available = match l {
+                0 => width.saturating_sub(self.cursor_initial.0 as usize).max(1),
+                _ => width,
+            };
+            let mut start = 0;
+            let mut used = 0;
+
+            for (i, g) in line.grapheme_indices(true) {
+                if used + g.width() > available && used > 0 {
+                    res.push(Row {
+                        line: l,
+                        start,
+                        end: i,
+                    });
+                    start = i;
+                    used = 0;
+                    available = width;
+                }
+                used += g.width();
+            }
+            res.push(Row {
+                line: l,
+                start,
+                end: line.len(),
+            });
+
+            // Room for the cursor after a full row
+            if used >= available && self.cursor == (line.len(), l) {
+                res.push(Row {
+                    line: l,
+                    start: line.len(),
+                    end: line.len(),
+                });
+            }
+        }
+
+        res
+    }
+
+    pub fn draw(&mut self, lua: &Lua) -> BoxedRes<()> {
+        if self.prompt_stale {
+            self.prompt_stale = false;
+            self.ps1 = prompt(lua)
+                .rsplit('\n')
+                .next()
+                .unwrap_or_default()
+                .to_string();
+            if !self.prompt_hidden {
+                queue!(
+                    stdout(),
+                    MoveTo(0, self.cursor_initial.1),
+                    Clear(ClearType::FromCursorDown)
+                )?;
+                self.reprint_prompt()?;
+            }
+        }
+
+        if self.redraw {
+            let code = self.code();
+            let mut styles = lua.context(|lua_ctx| self.highlighter.highlight(lua_ctx, &code));
+            if let Some((start, end)) = self.selection() {
+                for style in &mut styles[start..end] {
+                    style.attributes.set(Attribute::Reverse);
+                }
+            }
+
+            let (width, height) = size()?;
+            let (width, height) = (width as usize, height as usize);
+            let rows = self.rows(width);
+            let footer = self.footer();
+            let cursor_row = rows
+                .iter()
+                .rposition(|row| row.line == self.cursor.1 && row.start <= self.cursor.0)
+                .unwrap_or(0);
+
+            let mut stdout = stdout();
+
+            // Make room under the prompt by scrolling the terminal
+            let top = self.cursor_initial.1 as usize;
+            let overflow = (top + rows.len() + footer.len()).saturating_sub(height);
+            if overflow.min(top) > 0 {
+                queue!(stdout, ScrollUp(overflow.min(top) as u16))?;
+                self.cursor_initial.1 -= overflow.min(top) as u16;
+            }
+            let top = self.cursor_initial.1 as usize;
+
+            // What still doesn't fit is scrolled through, following the cursor
+            let visible = (height - top).saturating_sub(footer.len()).max(1);
+            self.scroll = match rows.len() <= visible {
+                true => 0,
+                false => self
+                    .scroll
+                    .min(cursor_row)
+                    .max((cursor_row + 1).saturating_sub(visible))
+                    .min(rows.len() - visible),
+            };
+
+            match (self.scroll, self.prompt_hidden) {
+                (0, false) => queue!(
+                    stdout,
+                    MoveTo(self.cursor_initial.0, self.cursor_initial.1),
+                    Clear(ClearType::FromCursorDown),
+                )?,
+                (0, true) => {
+                    queue!(
+                        stdout,
+                        MoveTo(0, self.cursor_initial.1),
+                        Clear(ClearType::FromCursorDown),
+                        Print(&self.ps1),
+                    )?;
+                    self.prompt_hidden = false;
+                }
+                _ => {
+                    queue!(
+                        stdout,
+                        MoveTo(0, self.cursor_initial.1),
+                        Clear(ClearType::FromCursorDown),
+                    )?;
+                    self.prompt_hidden = true;
+                }
+            }
+
+            let mut bases = vec![0];
+            for line in &self.cmd {
+                bases.push(bases[bases.len() - 1] + line.len() + 1);
+            }
+            let x = |i: usize| match i {
+                0 => self.cursor_initial.0,
+                _ => 0,
+            };
+
+            let shown = rows.len().min(visible);
+            for (i, row) in rows.iter().enumerate().skip(self.scroll).take(shown) {
+                let line = &self.cmd[row.line];
+                let base = bases[row.line];
+                queue!(
+                    stdout,
+                    MoveTo(x(i), (top + i - self.scroll) as u16),
+                    Print(highlight::paint(
+                        &line[row.start..row.end],
+                        &styles[base + row.start..base + row.end]
+                    )),
+                )?;
+            }
+            for (i, line) in footer.iter().enumerate() {
+                if top + shown + i < height {
+                    queue!(
+                        stdout,
+                        MoveTo(0, (top + shown + i) as u16),
+                        Print(clip(line, width))
+                    )?;
+                }
+            }
+
+            let (column, offset) = match &self.search {
+                Some(search) => (search.prompt().width(), shown),
+                None => {
+                    let row = &rows[cursor_row];
+                    let column = self.cmd[row.line][row.start..self.cursor.0].width();
+                    (x(cursor_row) as usize + column, cursor_row - self.scroll)
+                }
+            };
+            queue!(stdout, MoveTo(column as u16, (top + offset) as u16))?;
+            self.cursor_offset = offset as u16;
+
+            queue!(stdout, Show)?;
+
+            stdout.flush()?;
+
+            self.redraw = false;
+        }
+
+        Ok(())
+    }
+
+    /// The terminal rewrapped or moved what was on screen: find the prompt
+    /// from where the cursor ended up, and draw everything again from there.
+    pub fn resize(&mut self) -> BoxedRes<()> {
+        let top = position()?.1.saturating_sub(self.cursor_offset);
+        queue!(stdout(), MoveTo(0, top), Clear(ClearType::FromCursorDown))?;
+        self.reprint_prompt()
+    }
+
+    /// Clear the whole screen, leaving the prompt at the top.
+    pub fn clear_screen(&mut self) -> BoxedRes<()> {
+        queue!(stdout(), Clear(ClearType::All), MoveTo(0, 0))?;
+        self.reprint_prompt()
+    }
+
+    fn reprint_prompt(&mut self) -> BoxedRes<()> {
+        let mut stdout = stdout();
+        queue!(stdout, Print(&self.ps1))?;
+        stdout.flush()?;
+
+        self.cursor_initial = position()?;
+        self.prompt_hidden = false;
+        self.redraw = true;
+        Ok(())
+    }
+
+    /// Draw the whole buffer one last time, without what's shown under it,
+    /// and leave the cursor at its end.
+    pub fn finish(&mut self, lua: &Lua) -> BoxedRes<()> {
+        self.finished = true;
+        self.cursor_to_end();
+        // A last `end` isn't dedented until now
+        self.dedent_closer(true);
+        self.draw(lua)
+    }
+
+    /// Lines shown under the buffer.
+    fn footer(&self) -> Vec<String> {
+        if self.finished {
+            return vec![];
+        }
+        match (&self.search, &self.menu, &self.message) {
+            (Some(search), _, _) => vec![search.prompt()],
+            (None, Some(menu), _) => menu.lines(),
+            (None, None, Some(message)) => message.lines().map(String::from).collect(),
+            (None, None, None) => self
+                .highlighter
+                .diagnostic()
+                .map(String::from)
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    /// Display width of the cursor's line up to the cursor.
+    fn column(&self) -> usize {
+        self.cmd[self.cursor.1][..self.cursor.0].width()
+    }
+
+    fn cursor_to_end(&mut self) {
+        self.cursor.1 = self.cmd.len() - 1;
+        self.cursor.0 = self.cmd[self.cursor.1].len();
+        self.redraw = true;
+    }
+
+    pub fn code(&self) -> String {
+        self.cmd.join("\n")
+    }
+
+    /// Whether the buffer is a whole chunk, and not the beginning of one,
+    /// like an unclosed block, bracket or long string.
+    pub fn is_complete(&self, lua: &Lua) -> bool {
+        let code = self.code();
+        lua.context(|lua_ctx| {
+            // Bare expressions like `env.PATH` only parse with a `return`.
+            let expression = lua_ctx.load(&format!("return {}", code)).into_function();
+            expression.is_ok()
+                || !matches!(
+                    lua_ctx.load(&code).into_function(),
+                    Err(rlua::Error::SyntaxError {
+                        incomplete_input: true,
+                        ..
+                    })
+                )
+        })
+    }
+
+    /// Unindent a line starting with what closes a block, like `end`, if it's
+    /// still as indented as the previous line. Keywords only count once the
+    /// word is `finished` or followed by something else, so that `endpoint`
+    /// isn't taken for `end`.
+    fn dedent_closer(&mut self, finished: bool) {
+        let indent = |line: &str| line.len() - line.trim_start().len();
+
+        let line = &self.cmd[self.cursor.1];
+        let first = line.trim_start();
+        let closes = ["end", "else", "elseif", "until"].iter().any(|keyword| {
+            match first.strip_prefix(keyword) {
+                Some("") => finished,
+                Some(rest) => !is_word(rest),
+                None => false,
+            }
+        }) || first.starts_with(['}', ')', ']']);
+        let previous = match self.cursor.1 {
+            0 => return,
+            i => &self.cmd[i - 1],
+        };
+        // Right after the line opening the block, the body's indent is undone
+        let body = match opens_block(previous) {
+            true => indent(previous) + INDENT.len(),
+            false => indent(previous),
+        };
+        if closes && line.starts_with(INDENT) && indent(line) == body {
+            self.cmd[self.cursor.1].replace_range(..INDENT.len(), "");
+            self.cursor.0 = self.cursor.0.saturating_sub(INDENT.len());
+            self.redraw = true;
+        }
+    }
+
+    /// Break the line at the cursor, indenting the new line like the current
+    /// one, one level more after something opening a block.
+    pub fn newline(&mut self) {
+        self.dedent_closer(true);
+
+        let line = &self.cmd[self.cursor.1];
+        let before = &line[..self.cursor.0];
+        let mut indent: String = line.chars().take_while(|c| c.is_whitespace()).collect();
+
+        if opens_block(before) {
+            indent.push_str(INDENT);
+        }
+
+        self.add_char('\n');
+        self.cmd[self.cursor.1].insert_str(0, &indent);
+        self.cursor.0 = indent.len();
+    }
+
+    pub fn add_char(&mut self, c: char) {
+        self.history = None;
+
+        match c {
+            '\r' => {}
+            '\n' => {
+                let (line, new_line) = self.cmd[self.cursor.1].split_at(self.cursor.0);
+                let new_line = new_line.to_string();
+                let line = line.to_string();
+                self.cmd[self.cursor.1] = line;
+                self.cursor = (0, self.cursor.1 + 1);
+                self.cmd.insert(self.cursor.1, new_line);
+            }
+            c => {
+                self.cmd[self.cursor.1].insert(self.cursor.0, c);
+                self.cursor.0 += c.len_utf8();
+                self.dedent_closer(false);
+            }
+        }
+
+        self.redraw = true;
+    }
+
+    pub fn remove_char(&mut self) {
+        self.history = None;
+
+        match self.cursor.0 {
+            0 if self.cursor.1 > 0 => {
+                let line = self.cmd.remove(self.cursor.1);
+                self.cursor.1 -= 1;
+                self.cursor.0 = self.cmd[self.cursor.1].len();
+                self.cmd[self.cursor.1].push_str(&line);
+
+                self.redraw = true;
+            }
+            0 => {
+                // Nothing
+            }
+            x => {
+                let start = previous_grapheme(&self.cmd[self.cursor.1], x);
+                self.cmd[self.cursor.1].replace_range(start..x, "");
+                self.cursor.0 = start;
+
+                self.redraw = true;
+            }
+        }
+    }
+
+    pub fn left(&mut self) -> bool {
+        match self.cursor.0 {
+            0 => {
+                // Nothing
+                false
+            }
+            x => {
+                self.cursor.0 = previous_grapheme(&self.cmd[self.cursor.1], x);
+                self.redraw = true;
+                true
+            }
+        }
+    }
+
+    pub fn right(&mut self, wrapping: bool) -> bool {
+        match self.cursor.0 {
+            x if x == self.cmd[self.cursor.1].len() => {
+                if wrapping {
+                    if self.down() {
+                        self.cursor.0 = 0;
+                        true
+                    } else {
+                        false
+                    }
+                } else {
+                    // Nothing
+                    false
+                }
+            }
+            x => {
+                self.cursor.0 = next_grapheme(&self.cmd[self.cursor.1], x);
+                self.redraw = true;
+                true
+            }
+        }
+    }
+
+    pub fn up(&mut self) -> bool {
+        match self.cursor.1 {
+            0 => {
+                // Nothing
+                false
+            }
+            _ => {
+                let column = self.column();
+                self.cursor.1 -= 1;
+                self.cursor.0 = grapheme_at_column(&self.cmd[self.cursor.1], column);
+                self.redraw = true;
+                true
+            }
+        }
+    }
+
+    pub fn down(&mut self) -> bool {
+        match self.cursor.1 {
+            x if x < self.cmd.len() - 1 => {
+                let column = self.column();
+                self.cursor.1 += 1;
+                self.cursor.0 = grapheme_at_column(&self.cmd[self.cursor.1], column);
+                self.redraw = true;
+                true
+            }
+            _ => {
+                //Nothing
+                false
+            }
+        }
+    }
+
+    /// Byte offset of the cursor in `code()`.
+    pub fn offset(&self) -> usize {
+        self.cmd[..self.cursor.1]
+            .iter()
+            .map(|line| line.len() + 1)
+            .sum::<usize>()
+            + self.cursor.0
+    }
+
+    pub fn set_offset(&mut self, mut offset: usize) {
+        for (l, line) in self.cmd.iter().enumerate() {
+            if offset <= line.len() {
+                self.cursor = (offset, l);
+                break;
+            }
+            offset -= line.len() + 1;
+        }
+        self.redraw = true;
+    }
+
+    pub fn line_start(&mut self) {
+        self.cursor.0 = 0;
+        self.redraw = true;
+    }
+
+    pub fn line_end(&mut self) {
+        self.cursor.0 = self.cmd[self.cursor.1].len();
+        self.redraw = true;
+    }
+
+    pub fn word_left(&mut self) {
+        self.set_offset(word_start(&self.code(), self.offset(), is_word));
+    }
+
+    pub fn word_right(&mut self) {
+        self.set_offset(word_end(&self.code(), self.offset()));
+    }
+
+    /// Remove bytes `start..end` of the code and put them in the kill ring.
+    pub fn kill(&mut self, start: usize, end: usize) {
+        if start == end {
+            return;
+        }
+
+        let mut code = self.code();
+        let killed = code[start..end].to_string();
+        code.replace_range(start..end, "");
+        self.history = None;
+        self.set_code(&code);
+        self.set_offset(start);
+        push_kill(killed);
+    }
+
+    /// Ctrl-K: kill the rest of the line, or the line break at its end.
+    pub fn kill_line_end(&mut self) {
+        let offset = self.offset();
+        let end = match self.cmd[self.cursor.1].len() - self.cursor.0 {
+            0 if self.cursor.1 + 1 < self.cmd.len() => offset + 1,
+            rest => offset + rest,
+        };
+        self.kill(offset, end);
+    }
+
+    pub fn kill_line_start(&mut self) {
+        let offset = self.offset();
+        self.kill(offset - self.cursor.0, offset);
+    }
+
+    /// Ctrl-W: kill back to the previous whitespace.
+    pub fn kill_word_left(&mut self) {
+        let offset = self.offset();
+        let start = word_start(&self.code(), offset, |g| !g.trim().is_empty());
+        self.kill(start, offset);
+    }
+
+    pub fn kill_word_right(&mut self) {
+        let offset = self.offset();
+        self.kill(offset, word_end(&self.code(), offset));
+    }
+
+    pub fn insert(&mut self, text: &str) {
+        let offset = self.offset();
+        let mut code = self.code();
+        code.insert_str(offset, text);
+        self.history = None;
+        self.set_code(&code);
+        self.set_offset(offset + text.len());
+    }
+
+    /// Forget the last yank, for Alt-Y to only replace what the key just
+    /// before yanked.
+    pub fn forget_yank(&mut self) {
+        self.yanked = None;
+    }
+
+    /// Ctrl-Y: insert the last kill.
+    pub fn yank(&mut self) {
+        let last = KILL_RING.lock().unwrap().last().cloned();
+        if let Some(text) = last {
+            self.insert(&text);
+            self.yanked = Some(0);
+        }
+    }
+
+    /// Alt-Y: right after a yank, replace the yanked text by the kill before
+    /// it, going around the ring.
+    pub fn yank_pop(&mut self) {
+        let ring = KILL_RING.lock().unwrap().clone();
+        let (back, offset) = match self.yanked {
+            Some(back) if back < ring.len() => (back, self.offset()),
+            _ => return,
+        };
+        let text = &ring[ring.len() - 1 - back];
+        if !self.code()[..offset].ends_with(text.as_str()) {
+            self.yanked = None;
+            return;
+        }
+
+        let back = (back + 1) % ring.len();
+        self.set_offset(offset - text.len());
+        let mut code = self.code();
+        code.replace_range(offset - text.len()..offset, "");
+        self.set_code(&code);
+        self.insert(&ring[ring.len() - 1 - back]);
+        self.yanked = Some(back);
+    }
+
+    pub fn set_code(&mut self, code: &str) {
+        self.cmd = code.split('\n').map(|l| l.to_string()).collect();
+        self.redraw = true;
+    }
+
+    /// Replace the buffer with the previous history entry starting with what
+    /// was typed. The cursor lands on the first line, so Up keeps going back.
+    pub fn history_prev(&mut self) -> bool {
+        let codes = history::codes();
+        let (index, draft) = self
+            .history
+            .take()
+            .unwrap_or_else(|| (codes.len(), self.cmd.clone()));
+        let prefix = draft.join("\n");
+        let current = self.code();
+
+        match codes[..index]
+            .iter()
+            .rposition(|code| code.starts_with(&prefix) && *code != current)
+        {
+            Some(found) => {
+                self.set_code(&codes[found]);
+                self.cursor = (self.cmd[0].len(), 0);
+                self.history = Some((found, draft));
+                true
+            }
+            None => {
+                // Stay on the oldest match, if walking already
+                if index < codes.len() {
+                    self.history = Some((index, draft));
+                }
+                false
+            }
+        }
+    }
+
+    /// Go back towards the present, restoring the draft after the most
+    /// recent entry. The cursor lands on the last line, so Down keeps going.
+    pub fn history_next(&mut self) -> bool {
+        let (index, draft) = match self.history.take() {
+            Some(history) => history,
+            None => return false,
+        };
+        let codes = history::codes();
+        let prefix = draft.join("\n");
+        let current = self.code();
+
+        match codes[index + 1..]
+            .iter()
+            .position(|code| code.starts_with(&prefix) && *code != current)
+        {
+            Some(found) => {
+                let found = index + 1 + found;
+                self.set_code(&codes[found]);
+                self.history = Some((found, draft));
+            }
+            None => self.cmd = draft,
+        }
+        self.cursor_to_end();
+        true
+    }
+
+    /// Replace the word starting at `start` on the cursor's line, up to the
+    /// cursor, with `word`.
+    fn replace_word(&mut self, start: usize, word: &str) {
+        self.history = None;
+        self.cmd[self.cursor.1].replace_range(start..self.cursor.0, word);
+        self.cursor.0 = start + word.len();
+        self.redraw = true;
+    }
+
+    /// Hide the message shown under the buffer.
+    pub fn clear_message(&mut self) {
+        if self.message.take().is_some() {
+            self.redraw = true;
+        }
+    }
+
+    /// Complete the word before the cursor, as far as all candidates agree,
+    /// and open a menu when there are several. With the menu open, select the
+    /// next (previous if `backwards`) candidate instead.
+    pub fn complete(&mut self, lua: &Lua, backwards: bool) -> BoxedRes<()> {
+        if let Some(menu) = &mut self.menu {
+            let len = menu.candidates.len();
+            let selected = match (menu.selected, backwards) {
+                (None, false) => 0,
+                (None, true) => len - 1,
+                (Some(i), false) => (i + 1) % len,
+                (Some(i), true) => (i + len - 1) % len,
+            };
+            menu.selected = Some(selected);
+            let (start, candidate) = (menu.start, menu.candidates[selected].clone());
+            self.replace_word(start, &candidate);
+            return Ok(());
+        }
+
+        let offset = self.offset();
+        let code = self.code();
+        let completion = match lua.context(|lua_ctx| complete::complete(lua_ctx, &code, offset)) {
+            Ok(Some(completion)) => completion,
+            Ok(None) => return Ok(()),
+            // Most likely a broken completion provider
+            Err(e) => {
+                self.message = Some(error_message(&e));
+                self.redraw = true;
+                return Ok(());
+            }
+        };
+
+        let common = match completion.candidates.first() {
+            Some(first) => {
+                completion
+                    .candidates
+                    .iter()
+                    .fold(first.as_str(), |common, candidate| {
+                        let len = common
+                            .char_indices()
+                            .zip(candidate.chars())
+                            .find(|((_, a), b)| a != b)
+                            .map_or(common.len().min(candidate.len()), |((i, _), _)| i);
+                        &common[..len]
+                    })
+            }
+            None => return Ok(()),
+        };
+        let common = common.to_string();
+        self.replace_word(completion.start, &common);
+
+        if completion.candidates.len() > 1 {
+            self.menu = Some(Menu {
+                start: completion.start,
+                word: common,
+                candidates: completion.candidates,
+                selected: None,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Handle a key while the completion menu is open. Esc puts back the word
+    /// from before cycling and Enter keeps the selected candidate. Other keys
+    /// close the menu and are left to the editor, returning `false`.
+    pub fn menu_key(&mut self, code: KeyCode, modifiers: KeyModifiers) -> bool {
+        let menu = match &self.menu {
+            Some(menu) => menu,
+            None => return false,
+        };
+
+        match (code, modifiers) {
+            (KeyCode::Tab | KeyCode::BackTab, _) => false,
+            (KeyCode::Esc, _) => {
+                let (start, word) = (menu.start, menu.word.clone());
+                self.replace_word(start, &word);
+                self.menu = None;
+                true
+            }
+            (KeyCode::Enter, m) if m.is_empty() && menu.selected.is_some() => {
+                self.menu = None;
+                self.redraw = true;
+                true
+            }
+            _ => {
+                self.menu = None;
+                self.redraw = true;
+                false
+            }
+        }
+    }
+
+    pub fn start_search(&mut self) {
+        self.history = None;
+        self.search = Some(Search {
+            query: String::new(),
+            matches: vec![],
+            selected: 0,
+            draft: (self.cmd.clone(), self.cursor),
+        });
+        self.update_search();
+    }
+
+    fn update_search(&mut self) {
+        if let Some(search) = &mut self.search {
+            search.matches = history::search(&search.query);
+            search.selected = 0;
+        }
+        self.show_search_match();
+    }
+
+    fn show_search_match(&mut self) {
+        let search = match &self.search {
+            Some(search) => search,
+            None => return,
+        };
+        match search.matches.get(search.selected) {
+            Some(code) => {
+                let code = code.clone();
+                self.set_code(&code);
+                self.cursor_to_end();
+            }
+            None => {
+                self.cmd = search.draft.0.clone();
+                self.cursor = search.draft.1;
+            }
+        }
+        self.redraw = true;
+    }
+
+    /// Bytes of the code selected in vi's visual mode.
+    fn selection(&self) -> Option<(usize, usize)> {
+        self.vi.as_ref()?.selection(&self.code(), self.offset())
+    }
+
+    /// Handle a key with vi, if enabled. Keys vi doesn't use are left to the
+    /// editor, returning `false`.
+    pub fn vi_key(&mut self, code: KeyCode, modifiers: KeyModifiers) -> bool {
+        let mut vi = match self.vi.take() {
+            Some(vi) => vi,
+            None => return false,
+        };
+        let mode = vi.mode();
+        let res = vi.key(self, code, modifiers);
+        self.prompt_stale |= vi.mode() != mode;
+        self.redraw |= res;
+        self.vi = Some(vi);
+        res
+    }
+
+    /// Handle a key while searching. Keys the search doesn't use accept the
+    /// current match and are left to the editor, returning `false`.
+    pub fn search_key(&mut self, code: KeyCode, modifiers: KeyModifiers) -> bool {
+        let search = match &mut self.search {
+            Some(search) => search,
+            None => return false,
+        };
+
+        match (code, modifiers) {
+            (KeyCode::Char('r'), KeyModifiers::CONTROL) => {
+                search.selected = (search.selected + 1).min(search.matches.len().saturating_sub(1));
+                self.show_search_match();
+            }
+            (KeyCode::Char('s'), KeyModifiers::CONTROL) => {
+                search.selected = search.selected.saturating_sub(1);
+                self.show_search_match();
+            }
+            (KeyCode::Esc, _) | (KeyCode::Char('g'), KeyModifiers::CONTROL) => {
+                let (cmd, cursor) = search.draft.clone();
+                self.cmd = cmd;
+                self.cursor = cursor;
+                self.search = None;
+                self.redraw = true;
+            }
+            (KeyCode::Backspace, m) if m.is_empty() => {
+                search.query.pop();
+                self.update_search();
+            }
+            (KeyCode::Char(c), m) if m.is_empty() => {
+                search.query.push(c);
+                self.update_search();
+            }
+            (KeyCode::Char(c), KeyModifiers::SHIFT) => {
+                search.query.extend(c.to_uppercase());
+                self.update_search();
+            }
+            (KeyCode::Enter, m) if m.is_empty() => {
+                self.search = None;
+                self.redraw = true;
+            }
+            _ => {
+                self.search = None;
+                self.redraw = true;
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A buffer after a prompt `prompt` columns wide, without a terminal.
+    fn command(lines: &[&str], prompt: u16, cursor: (usize, usize)) -> Command {
+        Command {
+            cmd: lines.iter().map(|line| line.to_string()).collect(),
+            cursor_initial: (prompt, 0),
+            cursor,
+            redraw: false,
+            history: None,
+            search: None,
+            menu: None,
+            message: None,
+            highlighter: highlight::Highlighter::new(),
+            ps1: String::new(),
+            scroll: 0,
+            cursor_offset: 0,
+            prompt_hidden: false,
+            finished: false,
+            yanked: None,
+            vi: None,
+            prompt_stale: false,
+        }
+    }
+
+    fn rows(
+        lines: &[&str],
+        prompt: u16,
+        cursor: (usize, usize),
+        width: usize,
+    ) -> Vec<(usize, usize, usize)> {
+        command(lines, prompt, cursor)
+            .rows(width)
+            .iter()
+            .map(|row| (row.line, row.start, row.end))
+            .collect()
+    }
+
+    #[test]
+    fn wrapping() {
+        // The first row starts after the prompt
+        assert_eq!(
+            rows(&["abcdefghijkl"], 2, (0, 0), 10),
+            [(0, 0, 8), (0, 8, 12)]
+        );
+        assert_eq!(
+            rows(&["ab", "", "cd"], 2, (0, 0), 10),
+            [(0, 0, 2), (1, 0, 0), (2, 0, 2)]
+        );
+        // A prompt wider than the screen still leaves a column
+        assert_eq!(rows(&["ab"], 20, (0, 0), 10), [(0, 0, 1), (0, 1, 2)]);
+    }
+
+    #[test]
+    fn wide_graphemes() {
+        // Two columns and three bytes each
+        let line = "日本語テキスト";
+        assert_eq!(rows(&[line], 2, (0, 0), 10), [(0, 0, 12), (0, 12, 21)]);
+        // A wide grapheme that doesn't fit goes to the next row whole
+        assert_eq!(rows(&[line], 2, (0, 0), 9), [(0, 0, 9), (0, 9, 21)]);
+        // Combining marks take no room
+        let line = "e\u{301}e\u{301}e\u{301}";
+        assert_eq!(rows(&[line], 0, (0, 0), 2), [(0, 0, 6), (0, 6, 9)]);
+    }
+
+    #[test]
+    fn cursor_after_full_row() {
+        assert_eq!(rows(&["abcdefgh"], 2, (8, 0), 10), [(0, 0, 8), (0, 8, 8)]);
+        assert_eq!(rows(&["abcdefgh"], 2, (7, 0), 10), [(0, 0, 8)]);
+    }
+
+    #[test]
+    fn columns() {
+        let line = "a日本b";
+        assert_eq!(grapheme_at_column(line, 0), 0);
+        assert_eq!(grapheme_at_column(line, 1), 1);
+        assert_eq!(grapheme_at_column(line, 2), 1);
+        assert_eq!(grapheme_at_column(line, 3), 4);
+        assert_eq!(grapheme_at_column(line, 5), 7);
+        assert_eq!(grapheme_at_column(line, 6), line.len());
+    }
+
+    #[test]
+    fn clipping() {
+        assert_eq!(clip("abc", 5), "abc");
+        assert_eq!(clip("a日本b", 4), "a日");
+        assert_eq!(clip("a日本b", 5), "a日本");
+        // Escape codes are kept, even after the cut
+        assert_eq!(clip("\x1b[31mab\x1b[39mcd", 3), "\x1b[31mab\x1b[39mc");
+        assert_eq!(clip("\x1b[31mabc\x1b[39m", 1), "\x1b[31ma\x1b[39m");
+    }
+}
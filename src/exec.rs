@@ -0,0 +1,1194 @@
+use std::{
+    env, fmt, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::PathBuf,
+    process::{self, Stdio},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    builtin::{json_string, TableRes},
+    input, jobs,
+};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
+use is_executable::IsExecutable;
+use rlua::{Context, FromLua, MetaMethod, ToLua, UserData, Variadic};
+
+/// Registry table caching the result of every `PATH` lookup done through the
+/// globals `__index` metamethod. Misses are stored as `false`.
+const PATH_CACHE_KEY: &str = "__path_cache";
+
+/// Registry table mapping mangled executable names to their path, built the
+/// first time a mangled name is looked up.
+const PATH_MANGLED_KEY: &str = "__path_mangled";
+
+/// Registry sequence of the names of every executable in `PATH`, listed the
+/// first time completion needs them.
+const EXECUTABLE_NAMES_KEY: &str = "__executable_names";
+
+/// Registry value holding the mode forced by the innermost `tty` or `capture`
+/// call, if any.
+const EXEC_MODE_KEY: &str = "__exec_mode";
+
+/// How often Ctrl-C and Ctrl-Z are looked for while a pipeline runs.
+const SIGNAL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Lines read ahead of what's been handled. Readers wait past that, so a
+/// command writing faster than the shell keeps up can't fill the memory.
+const LINE_BUFFER: usize = 1024;
+
+/// A command invocation that can be configured before being run, wrapping
+/// `process::Command`. Every builder method returns a new handle, so the
+/// globals resolved from `PATH` are never modified.
+///
+/// ```lua
+/// git:args("status"):cwd("/tmp"):env("X", "1"):run()
+/// ```
+#[derive(Clone)]
+pub struct Cmd {
+    path: PathBuf,
+    args: Vec<String>,
+    cwd: Option<PathBuf>,
+    env: Vec<(String, Option<String>)>,
+    stdin: Option<Input>,
+    stdout: Option<Redirect>,
+    stderr: Option<Redirect>,
+    stream: bool,
+    interactive: Option<bool>,
+    should_tty: Arc<Mutex<bool>>,
+}
+
+/// Where a command reads its stdin from, instead of the terminal or the
+/// previous stage of a pipeline (`<`).
+#[derive(Clone)]
+enum Input {
+    File(PathBuf),
+    Bytes(Vec<u8>),
+}
+
+/// Where a command writes its stdout or stderr, instead of the terminal or
+/// the captured result (`>`, `>>`, `2>&1`).
+#[derive(Clone)]
+enum Redirect {
+    File { path: PathBuf, append: bool },
+    Stdout,
+}
+impl Redirect {
+    fn file(path: String, mode: Option<String>) -> rlua::Result<Self> {
+        let append = match mode.as_deref() {
+            None | Some("w") => false,
+            Some("a") => true,
+            Some(mode) => {
+                return Err(rlua::Error::RuntimeError(format!(
+                    "invalid redirection mode '{mode}', expected 'w' or 'a'"
+                )))
+            }
+        };
+        Ok(Redirect::File {
+            path: PathBuf::from(path),
+            append,
+        })
+    }
+}
+
+/// Serialize a Lua value fed to a command's stdin:
+/// - strings are passed as is,
+/// - the result of a captured command passes its `stdout`,
+/// - lists are written one item per line, or as a JSON array,
+/// - `TableRes` are written as TSV with a header line, or as JSON.
+///
+/// `format` is either `"lines"` (the default) or `"json"`.
+fn stdin_bytes<'lua>(
+    lua_ctx: Context<'lua>,
+    data: rlua::Value<'lua>,
+    format: Option<String>,
+) -> rlua::Result<Vec<u8>> {
+    let json = match format.as_deref() {
+        None | Some("lines") => false,
+        Some("json") => true,
+        Some(format) => {
+            return Err(rlua::Error::RuntimeError(format!(
+                "invalid stdin format '{format}', expected 'lines' or 'json'"
+            )))
+        }
+    };
+
+    match data {
+        rlua::Value::String(s) => Ok(s.as_bytes().to_vec()),
+        rlua::Value::UserData(data) => {
+            let table = data.borrow::<TableRes>()?;
+            Ok(match json {
+                true => table.to_json(),
+                false => table.to_tsv(),
+            }
+            .into_bytes())
+        }
+        rlua::Value::Table(table) => {
+            if let Some(stdout) = table.get::<_, Option<rlua::String>>("stdout")? {
+                let mut res = stdout.as_bytes().to_vec();
+                res.push(b'\n');
+                return Ok(res);
+            }
+
+            let lines = Vec::<String>::from_lua(rlua::Value::Table(table), lua_ctx)?;
+            Ok(match json {
+                true => format!(
+                    "[{}]",
+                    lines
+                        .iter()
+                        .map(|l| json_string(l))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ),
+                false => lines.iter().map(|l| format!("{l}\n")).collect(),
+            }
+            .into_bytes())
+        }
+        rlua::Value::Integer(_) | rlua::Value::Number(_) => {
+            Ok(String::from_lua(data, lua_ctx)?.into_bytes())
+        }
+        _ => Err(rlua::Error::RuntimeError(
+            "stdin expects a string, a list, a command result or a table".to_string(),
+        )),
+    }
+}
+
+impl Cmd {
+    pub fn new(path: PathBuf, should_tty: Arc<Mutex<bool>>) -> Self {
+        Cmd {
+            path,
+            args: vec![],
+            cwd: None,
+            env: vec![],
+            stdin: None,
+            stdout: None,
+            stderr: None,
+            stream: false,
+            interactive: None,
+            should_tty,
+        }
+    }
+
+    fn with_args<I: IntoIterator<Item = String>>(&self, args: I) -> Self {
+        let mut cmd = self.clone();
+        cmd.args.extend(args);
+        cmd
+    }
+
+    fn command(&self) -> process::Command {
+        let mut cmd = process::Command::new(&self.path);
+        cmd.args(&self.args);
+        if let Some(cwd) = &self.cwd {
+            cmd.current_dir(cwd);
+        }
+        for (key, value) in &self.env {
+            match value {
+                Some(value) => cmd.env(key, value),
+                None => cmd.env_remove(key),
+            };
+        }
+        cmd
+    }
+}
+impl fmt::Display for Cmd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.to_string_lossy())?;
+        for arg in &self.args {
+            write!(f, " {arg}")?;
+        }
+        Ok(())
+    }
+}
+impl UserData for Cmd {
+    fn add_methods<'lua, T: rlua::UserDataMethods<'lua, Self>>(methods: &mut T) {
+        methods.add_method("args", |_, cmd, args: Variadic<String>| {
+            Ok(cmd.with_args(args))
+        });
+        methods.add_method("cwd", |_, cmd, cwd: String| {
+            let mut cmd = cmd.clone();
+            cmd.cwd = Some(PathBuf::from(cwd));
+            Ok(cmd)
+        });
+        methods.add_method("env", |_, cmd, (key, value): (String, Option<String>)| {
+            let mut cmd = cmd.clone();
+            cmd.env.push((key, value));
+            Ok(cmd)
+        });
+        methods.add_method(
+            "stdin",
+            |lua_ctx, cmd, (data, format): (rlua::Value, Option<String>)| {
+                let mut cmd = cmd.clone();
+                cmd.stdin = Some(Input::Bytes(stdin_bytes(lua_ctx, data, format)?));
+                Ok(cmd)
+            },
+        );
+        methods.add_method("stdin_file", |_, cmd, path: String| {
+            let mut cmd = cmd.clone();
+            cmd.stdin = Some(Input::File(PathBuf::from(path)));
+            Ok(cmd)
+        });
+        methods.add_method(
+            "stdout",
+            |_, cmd, (path, mode): (String, Option<String>)| {
+                let mut cmd = cmd.clone();
+                cmd.stdout = Some(Redirect::file(path, mode)?);
+                Ok(cmd)
+            },
+        );
+        methods.add_method(
+            "stderr",
+            |_, cmd, (path, mode): (String, Option<String>)| {
+                let mut cmd = cmd.clone();
+                cmd.stderr = Some(Redirect::file(path, mode)?);
+                Ok(cmd)
+            },
+        );
+        methods.add_method("stderr_to_stdout", |_, cmd, ()| {
+            let mut cmd = cmd.clone();
+            cmd.stderr = Some(Redirect::Stdout);
+            Ok(cmd)
+        });
+        methods.add_method("stream", |_, cmd, ()| {
+            let mut cmd = cmd.clone();
+            cmd.stream = true;
+            Ok(cmd)
+        });
+        methods.add_method("interactive", |_, cmd, interactive: Option<bool>| {
+            let mut cmd = cmd.clone();
+            cmd.interactive = Some(interactive.unwrap_or(true));
+            Ok(cmd)
+        });
+        methods.add_method("lines", |lua_ctx, cmd, args: Variadic<String>| {
+            lines(lua_ctx, &[cmd.with_args(args)])
+        });
+        methods.add_method("spawn", |_, cmd, args: Variadic<String>| {
+            background(&[cmd.with_args(args)])
+        });
+        methods.add_method("run", |lua_ctx, cmd, args: Variadic<String>| {
+            run(lua_ctx, &[cmd.with_args(args)])
+        });
+        methods.add_meta_method(MetaMethod::Call, |lua_ctx, cmd, args: Variadic<String>| {
+            run(lua_ctx, &[cmd.with_args(args)])
+        });
+        methods.add_meta_function(MetaMethod::BOr, |lua_ctx, (a, b)| chain(lua_ctx, a, b));
+        methods.add_meta_method(MetaMethod::ToString, |_, cmd, ()| Ok(cmd.to_string()));
+    }
+}
+
+/// Commands chained with `|`, run with their stdout connected to the next
+/// command's stdin.
+#[derive(Clone)]
+pub struct Pipeline {
+    stages: Vec<Cmd>,
+}
+impl UserData for Pipeline {
+    fn add_methods<'lua, T: rlua::UserDataMethods<'lua, Self>>(methods: &mut T) {
+        methods.add_method("stream", |_, pipeline, ()| {
+            let mut pipeline = pipeline.clone();
+            if let Some(cmd) = pipeline.stages.last_mut() {
+                cmd.stream = true;
+            }
+            Ok(pipeline)
+        });
+        methods.add_method("interactive", |_, pipeline, interactive: Option<bool>| {
+            let mut pipeline = pipeline.clone();
+            if let Some(cmd) = pipeline.stages.last_mut() {
+                cmd.interactive = Some(interactive.unwrap_or(true));
+            }
+            Ok(pipeline)
+        });
+        methods.add_method("lines", |lua_ctx, pipeline, ()| {
+            lines(lua_ctx, &pipeline.stages)
+        });
+        methods.add_method("spawn", |_, pipeline, ()| background(&pipeline.stages));
+        methods.add_method("run", |lua_ctx, pipeline, ()| {
+            run(lua_ctx, &pipeline.stages)
+        });
+        methods.add_meta_method(MetaMethod::Call, |lua_ctx, pipeline, ()| {
+            run(lua_ctx, &pipeline.stages)
+        });
+        methods.add_meta_function(MetaMethod::BOr, |lua_ctx, (a, b)| chain(lua_ctx, a, b));
+        methods.add_meta_method(MetaMethod::ToString, |_, pipeline, ()| {
+            Ok(command_line(&pipeline.stages))
+        });
+    }
+}
+
+/// The commands described by a Lua value: a `Cmd`, a `Pipeline`, or an argv
+/// list like `{"ls", "-la"}`.
+fn stages<'lua>(
+    lua_ctx: Context<'lua>,
+    value: rlua::Value<'lua>,
+    should_tty: &Arc<Mutex<bool>>,
+) -> rlua::Result<Vec<Cmd>> {
+    match value {
+        rlua::Value::UserData(data) => {
+            if let Ok(cmd) = data.borrow::<Cmd>() {
+                Ok(vec![cmd.clone()])
+            } else if let Ok(pipeline) = data.borrow::<Pipeline>() {
+                Ok(pipeline.stages.clone())
+            } else {
+                Err(rlua::Error::RuntimeError(
+                    "expected a command or a pipeline".to_string(),
+                ))
+            }
+        }
+        rlua::Value::Table(table) => {
+            let mut argv = Vec::<String>::from_lua(rlua::Value::Table(table), lua_ctx)?;
+            if argv.is_empty() {
+                return Err(rlua::Error::RuntimeError(
+                    "empty pipeline stage".to_string(),
+                ));
+            }
+            let program = resolve_program(lua_ctx, &argv.remove(0))?;
+            Ok(vec![
+                Cmd::new(program, Arc::clone(should_tty)).with_args(argv)
+            ])
+        }
+        _ => Err(rlua::Error::RuntimeError(
+            "expected a command, a pipeline or an argv table".to_string(),
+        )),
+    }
+}
+
+/// `a | b`, where at least one side is a `Cmd` or a `Pipeline`.
+fn chain<'lua>(
+    lua_ctx: Context<'lua>,
+    a: rlua::Value<'lua>,
+    b: rlua::Value<'lua>,
+) -> rlua::Result<Pipeline> {
+    let should_tty = [&a, &b]
+        .iter()
+        .find_map(|v| match v {
+            rlua::Value::UserData(data) => data
+                .borrow::<Cmd>()
+                .map(|cmd| Arc::clone(&cmd.should_tty))
+                .or_else(|_| {
+                    data.borrow::<Pipeline>()
+                        .map(|pipeline| Arc::clone(&pipeline.stages[0].should_tty))
+                })
+                .ok(),
+            _ => None,
+        })
+        .ok_or_else(|| rlua::Error::RuntimeError("expected a command".to_string()))?;
+
+    let mut res = stages(lua_ctx, a, &should_tty)?;
+    res.extend(stages(lua_ctx, b, &should_tty)?);
+    Ok(Pipeline { stages: res })
+}
+
+/// Spawn `stages` in the background and register them as a job, returning
+/// its id.
+fn background(stages: &[Cmd]) -> rlua::Result<usize> {
+    let spawned = spawn(stages, Mode::Background).map_err(rlua::Error::external)?;
+    let pids = spawned.iter().map(|stage| stage.child.id() as i32);
+    Ok(jobs::background(
+        pgid(&spawned),
+        pids.collect(),
+        command_line(stages),
+    ))
+}
+
+/// Background a command, a pipeline or an argv list: `bg(cmd)`.
+pub fn spawn_background<'lua>(
+    lua_ctx: Context<'lua>,
+    value: rlua::Value<'lua>,
+    should_tty: &Arc<Mutex<bool>>,
+) -> rlua::Result<usize> {
+    background(&stages(lua_ctx, value, should_tty)?)
+}
+
+fn pgid(spawned: &[Spawned]) -> i32 {
+    spawned
+        .first()
+        .map(|stage| stage.child.id() as i32)
+        .unwrap_or(0)
+}
+
+fn command_line(stages: &[Cmd]) -> String {
+    stages
+        .iter()
+        .map(|cmd| cmd.to_string())
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+/// Whether `cmd` was explicitly asked to run on the terminal or captured,
+/// from the most specific to the least specific setting:
+/// - its own `interactive` flag,
+/// - the innermost `tty(fn)` or `capture(fn)` call,
+/// - the programs listed in `config.interactive`.
+///
+/// `None` leaves the decision to the heuristic applied to the whole chunk.
+fn requested_tty(lua_ctx: Context, cmd: &Cmd) -> rlua::Result<Option<bool>> {
+    if let Some(interactive) = cmd.interactive {
+        return Ok(Some(interactive));
+    }
+
+    match lua_ctx
+        .named_registry_value::<_, Option<String>>(EXEC_MODE_KEY)?
+        .as_deref()
+    {
+        Some("tty") => return Ok(Some(true)),
+        Some("capture") => return Ok(Some(false)),
+        _ => {}
+    }
+
+    let name = cmd.path.file_name().and_then(|name| name.to_str());
+    let listed = lua_ctx
+        .globals()
+        .get::<_, Option<rlua::Table>>("config")?
+        .and_then(|config| config.get::<_, Option<Vec<String>>>("interactive").ok())
+        .flatten()
+        .unwrap_or_default();
+    if name.map(|name| listed.iter().any(|v| v == name)) == Some(true) {
+        return Ok(Some(true));
+    }
+
+    Ok(None)
+}
+
+/// Call `f` with every command it runs forced on the terminal (`"tty"`) or
+/// captured (`"capture"`), whatever the heuristic says.
+pub fn with_mode<'lua>(
+    lua_ctx: Context<'lua>,
+    mode: &str,
+    f: rlua::Function<'lua>,
+    args: rlua::MultiValue<'lua>,
+) -> rlua::Result<rlua::MultiValue<'lua>> {
+    let previous = lua_ctx.named_registry_value::<_, rlua::Value>(EXEC_MODE_KEY)?;
+    lua_ctx.set_named_registry_value(EXEC_MODE_KEY, mode)?;
+    let res = f.call(args);
+    lua_ctx.set_named_registry_value(EXEC_MODE_KEY, previous)?;
+    res
+}
+
+/// Spawn `stages` as a pipeline, each stage's stdout feeding the next one's
+/// stdin through an OS pipe, and wait for all of them.
+///
+/// In tty mode the last stage writes straight to the terminal and `nil` is
+/// returned. Otherwise the last stage's stdout and every stage's stderr are
+/// captured into a result table, and also printed as they arrive when the
+/// last stage is streamed.
+fn run<'lua>(lua_ctx: Context<'lua>, stages: &[Cmd]) -> rlua::Result<rlua::Value<'lua>> {
+    let last = match stages.last() {
+        Some(cmd) => cmd,
+        None => return Ok(rlua::Value::Nil),
+    };
+    let (tty, from_heuristic) = match requested_tty(lua_ctx, last)? {
+        Some(tty) => (tty, false),
+        None => (*last.should_tty.lock().unwrap(), true),
+    };
+
+    if tty {
+        disable_raw_mode().map_err(rlua::Error::external)?;
+        let res = spawn(stages, Mode::Foreground).and_then(|spawned| {
+            let pids = spawned.iter().map(|stage| stage.child.id() as i32);
+            jobs::foreground(pgid(&spawned), pids.collect(), command_line(stages))
+        });
+        enable_raw_mode().map_err(rlua::Error::external)?;
+
+        if from_heuristic {
+            // The heuristic only ever hands the terminal to the first command
+            *last.should_tty.lock().unwrap() = false;
+        }
+
+        if let jobs::Wait::Stopped(id) = res.map_err(rlua::Error::external)? {
+            crate::print(&format!("\n[{id}] stopped\t{}\n", command_line(stages)))
+                .map_err(|e| rlua::Error::RuntimeError(e.to_string()))?;
+        }
+        return Ok(rlua::Value::Nil);
+    }
+
+    let (codes, stdout, stderr) = spawn(stages, Mode::Capture)
+        .and_then(|spawned| wait(spawned, last.stream, command_line(stages)))
+        .map_err(rlua::Error::external)?;
+
+    let table = lua_ctx.create_table()?;
+    table.set("code", codes.last().copied().flatten())?;
+    if codes.len() > 1 {
+        table.set("codes", lua_ctx.create_sequence_from(codes)?)?;
+    }
+    if let Some(cmd) = stages.last() {
+        table.set("path", cmd.path.to_string_lossy().to_string())?;
+    }
+    table.set(
+        "stdout",
+        String::from_utf8_lossy(&stdout).trim().to_string(),
+    )?;
+    table.set(
+        "stderr",
+        String::from_utf8_lossy(&stderr).trim().to_string(),
+    )?;
+    table.to_lua(lua_ctx)
+}
+
+type PipelineOutput = (Vec<Option<i32>>, Vec<u8>, Vec<u8>);
+
+/// The stdout of a stage being set up, kept around so `2>&1` can share it.
+enum Target {
+    Inherit,
+    File(fs::File),
+    Pipe(io::PipeWriter),
+}
+impl Target {
+    fn open(redirect: &Redirect) -> io::Result<Self> {
+        match redirect {
+            Redirect::File { path, append } => fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(*append)
+                .truncate(!*append)
+                .open(path)
+                .map(Target::File)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display()))),
+            Redirect::Stdout => Ok(Target::Inherit),
+        }
+    }
+
+    fn stdio(&self) -> io::Result<Stdio> {
+        Ok(match self {
+            Target::Inherit => Stdio::inherit(),
+            Target::File(file) => file.try_clone()?.into(),
+            Target::Pipe(pipe) => pipe.try_clone()?.into(),
+        })
+    }
+}
+
+/// Drain `reader` on a separate thread, so a chatty process can't fill a pipe
+/// nobody is reading and stall the whole pipeline.
+fn drain<R: Read + Send + 'static>(mut reader: R) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf).ok();
+        buf
+    })
+}
+
+/// How a pipeline is connected to the terminal. Each pipeline runs in its own
+/// process group.
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    /// Owns the terminal until it exits or gets stopped.
+    Foreground,
+    /// Output collected by the shell, nothing read from the terminal.
+    Capture,
+    /// Runs alongside the shell, writing to the terminal.
+    Background,
+}
+
+/// A spawned stage, with the ends of its pipes that the shell has to deal with.
+struct Spawned {
+    child: process::Child,
+    stdout: Option<io::PipeReader>,
+    stderr: Option<io::PipeReader>,
+    stdin: Option<thread::JoinHandle<()>>,
+}
+
+fn spawn_stage(
+    stage: &Cmd,
+    input: Option<Stdio>,
+    is_last: bool,
+    mode: Mode,
+    pgid: i32,
+) -> io::Result<Spawned> {
+    let mut cmd = stage.command();
+    jobs::prepare(&mut cmd, pgid, mode == Mode::Foreground);
+
+    match (&stage.stdin, input) {
+        (Some(Input::File(path)), _) => {
+            let file = fs::File::open(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+            cmd.stdin(file);
+        }
+        (Some(Input::Bytes(_)), _) => {
+            cmd.stdin(Stdio::piped());
+        }
+        (None, Some(input)) => {
+            cmd.stdin(input);
+        }
+        // Only the foreground process group may read from the terminal
+        (None, None) if mode != Mode::Foreground => {
+            cmd.stdin(Stdio::null());
+        }
+        (None, None) => {}
+    }
+
+    let mut stdout = None;
+    let target = match &stage.stdout {
+        Some(redirect) => Target::open(redirect)?,
+        None if !is_last || mode == Mode::Capture => {
+            let (reader, writer) = io::pipe()?;
+            stdout = Some(reader);
+            Target::Pipe(writer)
+        }
+        None => Target::Inherit,
+    };
+    cmd.stdout(target.stdio()?);
+
+    let mut stderr = None;
+    match &stage.stderr {
+        Some(Redirect::Stdout) => {
+            cmd.stderr(target.stdio()?);
+        }
+        Some(redirect) => {
+            cmd.stderr(Target::open(redirect)?.stdio()?);
+        }
+        None if mode != Mode::Capture => {
+            cmd.stderr(Stdio::inherit());
+        }
+        None => {
+            let (reader, writer) = io::pipe()?;
+            stderr = Some(reader);
+            cmd.stderr(writer);
+        }
+    }
+
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", stage.path.display())))?;
+
+    // Our copies of the write ends have to be closed, or readers never see EOF
+    drop(cmd);
+    drop(target);
+
+    let stdin = match (&stage.stdin, child.stdin.take()) {
+        (Some(Input::Bytes(bytes)), Some(mut child_stdin)) => {
+            let bytes = bytes.clone();
+            Some(thread::spawn(move || {
+                // The child may exit without reading everything, that's fine
+                child_stdin.write_all(&bytes).ok();
+            }))
+        }
+        _ => None,
+    };
+
+    Ok(Spawned {
+        child,
+        stdout,
+        stderr,
+        stdin,
+    })
+}
+
+/// Spawn every stage in a new process group, connecting each one's stdout to
+/// the next one's stdin. The last stage's stdout is only piped back to the
+/// shell when capturing.
+fn spawn(stages: &[Cmd], mode: Mode) -> io::Result<Vec<Spawned>> {
+    let mut spawned: Vec<Spawned> = vec![];
+    let mut input: Option<Stdio> = None;
+    let mut pgid = 0;
+
+    for (i, stage) in stages.iter().enumerate() {
+        let is_last = i == stages.len() - 1;
+
+        match spawn_stage(stage, input.take(), is_last, mode, pgid) {
+            Ok(mut stage) => {
+                let pid = stage.child.id() as i32;
+                if pgid == 0 {
+                    pgid = pid;
+                }
+                jobs::set_group(pid, pgid);
+
+                if !is_last {
+                    // A stage redirected to a file gives nothing to the next one
+                    input = Some(match stage.stdout.take() {
+                        Some(reader) => reader.into(),
+                        None => Stdio::null(),
+                    });
+                }
+                spawned.push(stage);
+            }
+            Err(e) => {
+                kill(spawned);
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(spawned)
+}
+
+fn kill(spawned: Vec<Spawned>) {
+    for mut stage in spawned {
+        stage.child.kill().ok();
+        stage.child.wait().ok();
+    }
+}
+
+/// Read whatever the pipeline outputs and wait for every stage to exit.
+/// Ctrl-C is forwarded to the pipeline's process group meanwhile. On Ctrl-Z
+/// the pipeline becomes a stopped job and what it output so far is returned.
+///
+/// When streaming, lines are printed as soon as they arrive, in the order the
+/// processes wrote them, on top of being collected.
+fn wait(mut spawned: Vec<Spawned>, stream: bool, command: String) -> io::Result<PipelineOutput> {
+    let pgid = pgid(&spawned);
+    // Whether the pipeline got stopped
+    let forward_signal = || match input::signal() {
+        Some(libc::SIGINT) => {
+            jobs::interrupt(pgid);
+            false
+        }
+        Some(libc::SIGTSTP) => {
+            jobs::suspend(pgid);
+            true
+        }
+        _ => false,
+    };
+
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+
+    let (tx, rx) = mpsc::sync_channel::<(bool, Vec<u8>)>(LINE_BUFFER);
+
+    let mut readers = vec![];
+    if let Some(reader) = spawned.last_mut().and_then(|stage| stage.stdout.take()) {
+        readers.push((false, reader));
+    }
+    readers.extend(
+        spawned
+            .iter_mut()
+            .filter_map(|stage| stage.stderr.take())
+            .map(|reader| (true, reader)),
+    );
+
+    for (is_err, reader) in readers {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            loop {
+                let mut line = vec![];
+                match reader.read_until(b'\n', &mut line) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {
+                        // Once stopped, the job's output goes to the terminal
+                        if let Err(mpsc::SendError((_, line))) = tx.send((is_err, line)) {
+                            io::stdout().write_all(&line).ok();
+                        }
+                    }
+                }
+            }
+        });
+    }
+    drop(tx);
+
+    // Checked by time, not when the pipeline goes quiet, as `yes` never does
+    let mut checked = Instant::now();
+    loop {
+        if checked.elapsed() >= SIGNAL_INTERVAL {
+            checked = Instant::now();
+            if forward_signal() {
+                return stop(spawned, command, stdout, stderr);
+            }
+        }
+        match rx.recv_timeout(SIGNAL_INTERVAL) {
+            Ok((is_err, line)) => {
+                if stream {
+                    crate::print(&String::from_utf8_lossy(&line))
+                        .map_err(|e| io::Error::other(e.to_string()))?;
+                }
+                match is_err {
+                    true => stderr.extend(line),
+                    false => stdout.extend(line),
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    let mut codes = vec![];
+    for i in 0..spawned.len() {
+        let status = loop {
+            match spawned[i].child.try_wait()? {
+                Some(status) => break status,
+                None if forward_signal() => return stop(spawned, command, stdout, stderr),
+                None => thread::sleep(SIGNAL_INTERVAL),
+            }
+        };
+        codes.push(status.code());
+        if let Some(handle) = spawned[i].stdin.take() {
+            handle.join().ok();
+        }
+    }
+
+    Ok((codes, stdout, stderr))
+}
+
+/// Register a pipeline stopped with Ctrl-Z as a job, and give back what it
+/// output until then.
+fn stop(
+    spawned: Vec<Spawned>,
+    command: String,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+) -> io::Result<PipelineOutput> {
+    let pids = spawned.iter().map(|stage| stage.child.id() as i32);
+    let id = jobs::stopped(pgid(&spawned), pids.collect(), command.clone());
+    crate::print(&format!("\n[{id}] stopped\t{command}\n"))
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    Ok((vec![None; spawned.len()], stdout, stderr))
+}
+
+/// The pipeline's output lines, read as the last stage produces them.
+/// Dropping it before the end kills the remaining processes.
+struct LineReader {
+    /// Lines read on a thread, so that Ctrl-C is noticed while the pipeline
+    /// is quiet
+    lines: Option<mpsc::Receiver<Vec<u8>>>,
+    spawned: Vec<Spawned>,
+    /// When Ctrl-C was last looked for
+    checked: Instant,
+}
+impl LineReader {
+    fn new(reader: Option<io::PipeReader>, spawned: Vec<Spawned>) -> Self {
+        let lines = reader.map(|reader| {
+            let (tx, rx) = mpsc::sync_channel(LINE_BUFFER);
+            thread::spawn(move || {
+                let mut reader = BufReader::new(reader);
+                loop {
+                    let mut line = vec![];
+                    match reader.read_until(b'\n', &mut line) {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => {
+                            if tx.send(line).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+            });
+            rx
+        });
+        LineReader {
+            lines,
+            spawned,
+            checked: Instant::now(),
+        }
+    }
+
+    fn next_line(&mut self) -> io::Result<Option<String>> {
+        let lines = match self.lines.as_ref() {
+            Some(lines) => lines,
+            None => return Ok(None),
+        };
+
+        let mut line = loop {
+            if self.checked.elapsed() >= SIGNAL_INTERVAL {
+                self.checked = Instant::now();
+                if input::interrupted() {
+                    jobs::interrupt(pgid(&self.spawned));
+                }
+            }
+            match lines.recv_timeout(SIGNAL_INTERVAL) {
+                Ok(line) => break line,
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    self.lines = None;
+                    for mut stage in self.spawned.drain(..) {
+                        stage.child.wait()?;
+                    }
+                    return Ok(None);
+                }
+            }
+        };
+
+        if line.ends_with(b"\n") {
+            line.pop();
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+        }
+        Ok(Some(String::from_utf8_lossy(&line).to_string()))
+    }
+}
+impl Drop for LineReader {
+    fn drop(&mut self) {
+        self.lines = None;
+        kill(self.spawned.drain(..).collect());
+    }
+}
+
+/// An iterator function over the pipeline's output lines, for
+/// `for line in cmd:lines() do ... end`. Stderr is discarded unless redirected.
+fn lines<'lua>(lua_ctx: Context<'lua>, stages: &[Cmd]) -> rlua::Result<rlua::Function<'lua>> {
+    let mut spawned = spawn(stages, Mode::Capture).map_err(rlua::Error::external)?;
+
+    for stage in spawned.iter_mut() {
+        if let Some(reader) = stage.stderr.take() {
+            drain(reader);
+        }
+    }
+
+    let reader = spawned.last_mut().and_then(|stage| stage.stdout.take());
+    let line_reader = Mutex::new(LineReader::new(reader, spawned));
+
+    lua_ctx.create_function(move |_, ()| {
+        line_reader
+            .lock()
+            .unwrap()
+            .next_line()
+            .map_err(rlua::Error::external)
+    })
+}
+
+/// Resolve the program of a pipeline stage: paths are used as is, bare names
+/// go through `PATH`.
+fn resolve_program(lua_ctx: Context, name: &str) -> rlua::Result<PathBuf> {
+    let path = if name.contains('/') {
+        Some(PathBuf::from(name))
+    } else {
+        match lookup(name) {
+            Some(path) => Some(path),
+            None => lookup_mangled(lua_ctx, name)?,
+        }
+    };
+
+    path.ok_or_else(|| rlua::Error::RuntimeError(format!("command not found: {name}")))
+}
+
+/// Run a pipeline whose stages are commands, pipelines or argv lists:
+/// `pipe(ls:args("-la"), {"grep", "rs"})`.
+pub fn pipe<'lua>(
+    lua_ctx: Context<'lua>,
+    values: Vec<rlua::Value<'lua>>,
+    should_tty: &Arc<Mutex<bool>>,
+) -> rlua::Result<rlua::Value<'lua>> {
+    let mut res = vec![];
+    for value in values {
+        res.extend(stages(lua_ctx, value, should_tty)?);
+    }
+
+    run(lua_ctx, &res)
+}
+
+/// Resolve `name` against the current `PATH`, the same way `execvp` would.
+pub fn lookup(name: &str) -> Option<PathBuf> {
+    if name.is_empty() || name.contains('/') {
+        return None;
+    }
+
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_executable())
+}
+
+/// Every executable reachable from the current `PATH`. When a name appears in
+/// several directories, the first one wins, like in any other shell.
+pub fn executables() -> Vec<(String, PathBuf)> {
+    let mut res: Vec<(String, PathBuf)> = vec![];
+
+    let paths = match env::var_os("PATH") {
+        Some(paths) => paths,
+        None => return res,
+    };
+
+    for dir in env::split_paths(&paths) {
+        if let Ok(entries) = fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                if let Some(name) = entry.file_name().to_str() {
+                    let path = entry.path();
+                    if path.is_executable() && !res.iter().any(|(n, _)| n == name) {
+                        res.push((name.to_string(), path));
+                    }
+                }
+            }
+        }
+    }
+
+    res
+}
+
+/// Turn an executable name into a valid Lua identifier: every character that
+/// can't appear in an identifier becomes `_`, and names starting with a digit
+/// get a leading `_` (`git-lfs` -> `git_lfs`, `7z` -> `_7z`).
+pub fn mangle(name: &str) -> String {
+    let mut res: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if res.starts_with(|c: char| c.is_ascii_digit()) {
+        res.insert(0, '_');
+    }
+    res
+}
+
+/// Names of every executable in `PATH`, cached until `rehash`.
+pub fn executable_names(lua_ctx: Context) -> rlua::Result<Vec<String>> {
+    if let Ok(names) = lua_ctx.named_registry_value::<_, Vec<String>>(EXECUTABLE_NAMES_KEY) {
+        return Ok(names);
+    }
+
+    let names = executables()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    lua_ctx.set_named_registry_value(EXECUTABLE_NAMES_KEY, names.clone())?;
+    Ok(names)
+}
+
+/// Whether the global `name` would resolve to an executable, looking only at
+/// what's cached, so that nothing runs while the user types.
+pub fn is_command(lua_ctx: Context, name: &str) -> rlua::Result<bool> {
+    let cache = lua_ctx.named_registry_value::<_, rlua::Table>(PATH_CACHE_KEY)?;
+    match cache.raw_get::<_, rlua::Value>(name)? {
+        rlua::Value::Nil => {}
+        rlua::Value::Boolean(false) => return Ok(false),
+        _ => return Ok(true),
+    }
+    Ok(executable_names(lua_ctx)?
+        .iter()
+        .any(|exe| exe == name || (name.contains('_') && mangle(exe) == name)))
+}
+
+/// Find the executable whose mangled name is `name`, building the mangled
+/// index on first use.
+fn lookup_mangled(lua_ctx: Context, name: &str) -> rlua::Result<Option<PathBuf>> {
+    // A name without `_` is its own mangled form, and was already looked up
+    if !name.contains('_') {
+        return Ok(None);
+    }
+
+    let mangled = match lua_ctx.named_registry_value::<_, rlua::Table>(PATH_MANGLED_KEY) {
+        Ok(mangled) => mangled,
+        Err(_) => {
+            let mangled = lua_ctx.create_table()?;
+            for (exe, path) in executables() {
+                let key = mangle(&exe);
+                if key != exe && !mangled.contains_key(&*key)? {
+                    mangled.set(key, path.to_str().map(|v| v.to_string()))?;
+                }
+            }
+            lua_ctx.set_named_registry_value(PATH_MANGLED_KEY, mangled.clone())?;
+            mangled
+        }
+    };
+
+    Ok(mangled.get::<_, Option<String>>(name)?.map(PathBuf::from))
+}
+
+/// Resolve `name` to a `Cmd` for the matching executable, trying the exact
+/// name first and then the mangled ones. Results are cached until `rehash`.
+fn resolve<'lua>(
+    lua_ctx: Context<'lua>,
+    name: &str,
+    should_tty: &Arc<Mutex<bool>>,
+) -> rlua::Result<rlua::Value<'lua>> {
+    let cache = lua_ctx.named_registry_value::<_, rlua::Table>(PATH_CACHE_KEY)?;
+    match cache.raw_get::<_, rlua::Value>(name)? {
+        rlua::Value::Nil => {}
+        rlua::Value::Boolean(false) => return Ok(rlua::Value::Nil),
+        cmd => return Ok(cmd),
+    }
+
+    let path = match lookup(name) {
+        Some(path) => Some(path),
+        None => lookup_mangled(lua_ctx, name)?,
+    };
+
+    match path {
+        Some(path) => {
+            let cmd = lua_ctx.create_userdata(Cmd::new(path, Arc::clone(should_tty)))?;
+            cache.raw_set(name, cmd.clone())?;
+            Ok(rlua::Value::UserData(cmd))
+        }
+        None => {
+            cache.raw_set(name, false)?;
+            Ok(rlua::Value::Nil)
+        }
+    }
+}
+
+/// Make unknown globals resolve to executables found in `PATH`. Lookups only
+/// happen on first access, and any global defined from Lua shadows them.
+///
+/// Also registers the `cmd` table, for executables whose name isn't a valid
+/// identifier: `cmd["git-lfs"]("pull")`.
+pub fn install(lua_ctx: Context, should_tty: Arc<Mutex<bool>>) -> rlua::Result<()> {
+    rehash(lua_ctx)?;
+
+    let index = |should_tty: Arc<Mutex<bool>>| {
+        lua_ctx.create_function(
+            move |lua_ctx, (_, name): (rlua::Table, rlua::Value)| match name {
+                rlua::Value::String(name) => resolve(lua_ctx, name.to_str()?, &should_tty),
+                _ => Ok(rlua::Value::Nil),
+            },
+        )
+    };
+
+    let meta = lua_ctx.create_table()?;
+    meta.set("__index", index(Arc::clone(&should_tty))?)?;
+    lua_ctx.globals().set_metatable(Some(meta));
+
+    let cmd = lua_ctx.create_table()?;
+    let meta = lua_ctx.create_table()?;
+    meta.set("__index", index(should_tty)?)?;
+    cmd.set_metatable(Some(meta));
+    lua_ctx.globals().set("cmd", cmd)?;
+
+    Ok(())
+}
+
+/// Forget every cached `PATH` lookup.
+pub fn rehash(lua_ctx: Context) -> rlua::Result<()> {
+    lua_ctx.set_named_registry_value(PATH_CACHE_KEY, lua_ctx.create_table()?)?;
+    lua_ctx.set_named_registry_value(PATH_MANGLED_KEY, rlua::Value::Nil)?;
+    lua_ctx.set_named_registry_value(EXECUTABLE_NAMES_KEY, rlua::Value::Nil)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rlua::Lua;
+
+    #[test]
+    fn mangling() {
+        assert_eq!(mangle("git-lfs"), "git_lfs");
+        assert_eq!(mangle("7z"), "_7z");
+        assert_eq!(mangle("x86_64-linux-gnu-gcc"), "x86_64_linux_gnu_gcc");
+        assert_eq!(mangle("g++"), "g__");
+        assert_eq!(mangle("my tool.sh"), "my_tool_sh");
+        assert_eq!(mangle("café"), "caf_");
+    }
+
+    #[test]
+    fn stdin_formats() {
+        Lua::new().context(|lua_ctx| {
+            let bytes = |code: &str, format: Option<&str>| {
+                let value = lua_ctx.load(code).eval::<rlua::Value>().unwrap();
+                stdin_bytes(lua_ctx, value, format.map(String::from))
+                    .map(|bytes| String::from_utf8(bytes).unwrap())
+            };
+
+            assert_eq!(bytes("'as is\\n'", None).unwrap(), "as is\n");
+            assert_eq!(bytes("42", None).unwrap(), "42");
+            assert_eq!(
+                bytes("{'/tmp/my dir', 'b'}", None).unwrap(),
+                "/tmp/my dir\nb\n"
+            );
+            assert_eq!(
+                bytes("{'say \"hi\"', 'a\\\\b\\tc'}", Some("json")).unwrap(),
+                "[\"say \\\"hi\\\"\",\"a\\\\b\\tc\"]"
+            );
+            assert_eq!(
+                bytes("{stdout = 'out', code = 0}", Some("json")).unwrap(),
+                "out\n"
+            );
+            assert!(bytes("{}", Some("yaml")).is_err());
+            assert!(bytes("true", None).is_err());
+
+            let table = TableRes {
+                header: vec!["name".to_string(), "size".to_string()],
+                entries: vec![vec!["my file.txt".to_string(), "3".to_string()]],
+            };
+            let value = rlua::Value::UserData(lua_ctx.create_userdata(table).unwrap());
+            let tsv = stdin_bytes(lua_ctx, value.clone(), None).unwrap();
+            assert_eq!(tsv, b"name\tsize\nmy file.txt\t3\n");
+            let json = stdin_bytes(lua_ctx, value, Some("json".to_string())).unwrap();
+            assert_eq!(json, b"[{\"name\":\"my file.txt\",\"size\":\"3\"}]");
+        });
+    }
+}
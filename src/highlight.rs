@@ -0,0 +1,352 @@
+use std::collections::HashSet;
+
+use crossterm::style::{Attribute, Color, ContentStyle, Stylize};
+use rlua::Context;
+use tree_sitter::{InputEdit, Node, Parser, Point, Tree};
+
+use crate::{complete::KEYWORDS, exec};
+
+/// What a part of the code is, for coloring.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Keyword,
+    String,
+    Number,
+    Comment,
+    /// A call to a function that exists
+    Call,
+    UnknownCall,
+    /// Syntax error messages
+    Error,
+}
+impl Kind {
+    /// Key of the color in `config.colors`.
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Keyword => "keyword",
+            Kind::String => "string",
+            Kind::Number => "number",
+            Kind::Comment => "comment",
+            Kind::Call => "call",
+            Kind::UnknownCall => "unknown_call",
+            Kind::Error => "error",
+        }
+    }
+}
+
+/// A color name known to crossterm, like `dark_grey`, or `#rrggbb`.
+fn parse_color(s: &str) -> Option<Color> {
+    match s.strip_prefix('#') {
+        Some(hex) if hex.len() == 6 => {
+            let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+            Some(Color::Rgb {
+                r: channel(0)?,
+                g: channel(2)?,
+                b: channel(4)?,
+            })
+        }
+        _ => Color::try_from(s).ok(),
+    }
+}
+
+fn point(code: &str, byte: usize) -> Point {
+    let before = &code[..byte];
+    Point {
+        row: before.matches('\n').count(),
+        column: byte - before.rfind('\n').map_or(0, |i| i + 1),
+    }
+}
+
+/// The part of `new` that differs from `old`, as tree-sitter wants it.
+fn diff(old: &str, new: &str) -> InputEdit {
+    let mut start = old
+        .bytes()
+        .zip(new.bytes())
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !old.is_char_boundary(start) || !new.is_char_boundary(start) {
+        start -= 1;
+    }
+
+    let max_suffix = old.len().min(new.len()) - start;
+    let mut suffix = old
+        .bytes()
+        .rev()
+        .zip(new.bytes().rev())
+        .take(max_suffix)
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !old.is_char_boundary(old.len() - suffix) || !new.is_char_boundary(new.len() - suffix) {
+        suffix -= 1;
+    }
+
+    let old_end = old.len() - suffix;
+    let new_end = new.len() - suffix;
+    InputEdit {
+        start_byte: start,
+        old_end_byte: old_end,
+        new_end_byte: new_end,
+        start_position: point(old, start),
+        old_end_position: point(old, old_end),
+        new_end_position: point(new, new_end),
+    }
+}
+
+/// Names given a value in the code, which calls may refer to.
+fn declared<'a>(node: Node, code: &'a str, res: &mut HashSet<&'a str>) {
+    if node.kind() == "identifier" {
+        if let Some(parent) = node.parent() {
+            if let "variable_list"
+            | "parameters"
+            | "for_numeric_clause"
+            | "for_generic_clause"
+            | "function_declaration" = parent.kind()
+            {
+                res.insert(&code[node.byte_range()]);
+            }
+        }
+    }
+
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        declared(child, code, res);
+    }
+}
+
+/// Whether the global, or the `.` separated path from the globals, exists.
+/// Anything more complex is assumed to.
+///
+/// This runs on every redraw, so `__index` metamethods are bypassed: they
+/// could loop or have side effects. Commands are only looked for in the
+/// `PATH` caches.
+fn exists(lua_ctx: Context, path: &str) -> bool {
+    if !path
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '_' || c == '.' || c.is_whitespace())
+    {
+        return true;
+    }
+
+    let parts = path.split('.').map(str::trim).collect::<Vec<_>>();
+    let mut value = rlua::Value::Table(lua_ctx.globals());
+    for part in &parts {
+        value = match value {
+            rlua::Value::Table(table) => match table.raw_get(*part) {
+                Ok(value) => value,
+                Err(_) => return false,
+            },
+            _ => return false,
+        };
+    }
+    match (value, parts.as_slice()) {
+        (rlua::Value::Nil, [name] | ["cmd", name]) => {
+            exec::is_command(lua_ctx, name).unwrap_or(false)
+        }
+        (value, _) => !matches!(value, rlua::Value::Nil),
+    }
+}
+
+fn spans(
+    node: Node,
+    code: &str,
+    known: &mut dyn FnMut(&str) -> bool,
+    res: &mut Vec<(usize, usize, Kind)>,
+) {
+    let kind = match node.kind() {
+        "string" => Some(Kind::String),
+        "number" => Some(Kind::Number),
+        "comment" => Some(Kind::Comment),
+        kind if KEYWORDS.contains(&kind) => Some(Kind::Keyword),
+        _ => None,
+    };
+    if let Some(kind) = kind {
+        res.push((node.start_byte(), node.end_byte(), kind));
+        return;
+    }
+
+    if node.kind() == "function_call" {
+        let name = node.child_by_field_name("name");
+        let callee = name.and_then(|name| match name.kind() {
+            "identifier" => Some((name, known(&code[name.byte_range()]))),
+            "dot_index_expression" => name
+                .child_by_field_name("field")
+                .map(|field| (field, known(&code[name.byte_range()]))),
+            // Methods of userdata can't be listed
+            "method_index_expression" => name.child_by_field_name("method").map(|m| (m, true)),
+            _ => None,
+        });
+        if let Some((callee, known)) = callee {
+            let kind = match known {
+                true => Kind::Call,
+                false => Kind::UnknownCall,
+            };
+            res.push((callee.start_byte(), callee.end_byte(), kind));
+        }
+    }
+
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        spans(child, code, known, res);
+    }
+}
+
+/// ERROR and MISSING nodes, without the errors nested in other errors.
+fn errors<'tree>(node: Node<'tree>, res: &mut Vec<Node<'tree>>) {
+    if node.is_error() || node.is_missing() {
+        res.push(node);
+        return;
+    }
+    if !node.has_error() {
+        return;
+    }
+
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        errors(child, res);
+    }
+}
+
+/// Whether the error goes on until the end of the code, most likely because
+/// it's still being typed.
+fn unfinished(node: Node, code: &str) -> bool {
+    node.end_byte() >= code.trim_end().len()
+}
+
+/// A short description of a syntax error, with its position.
+fn describe(node: Node, code: &str) -> String {
+    let start = node.start_position();
+    let line = code.split('\n').nth(start.row).unwrap_or_default();
+    let column = line[..start.column.min(line.len())].chars().count() + 1;
+
+    let message = match node.is_missing() {
+        true if node.is_named() => format!("missing {}", node.kind()),
+        true => format!("missing `{}`", node.kind()),
+        false => {
+            let what = match unfinished(node, code) {
+                true => "unfinished",
+                false => "unexpected",
+            };
+            let text = code[node.byte_range()].lines().next().unwrap_or_default();
+            match text.trim() {
+                "" => "syntax error".to_string(),
+                text if text.chars().count() > 20 => {
+                    format!("{what} `{}...`", text.chars().take(20).collect::<String>())
+                }
+                text => format!("{what} `{text}`"),
+            }
+        }
+    };
+
+    format!("{}:{column}: {message}", start.row + 1)
+}
+
+/// `text` with escape codes for the style of each of its bytes in `styles`.
+pub fn paint(text: &str, styles: &[ContentStyle]) -> String {
+    let mut res = String::new();
+    let mut run_start = 0;
+
+    for (i, _) in text.char_indices().skip(1) {
+        if styles[i] != styles[run_start] {
+            res.push_str(&styles[run_start].apply(&text[run_start..i]).to_string());
+            run_start = i;
+        }
+    }
+    if run_start < text.len() {
+        res.push_str(&styles[run_start].apply(&text[run_start..]).to_string());
+    }
+
+    res
+}
+
+/// Syntax highlighting of the editor buffer. The parse tree is kept between
+/// draws, so only the edited part gets parsed again.
+pub struct Highlighter {
+    parser: Parser,
+    tree: Option<Tree>,
+    code: String,
+    /// The first syntax error of the code, colored
+    diagnostic: Option<String>,
+}
+impl Highlighter {
+    pub fn new() -> Self {
+        let mut parser = Parser::new();
+        parser
+            .set_language(tree_sitter_lua::language())
+            .expect("Lua grammar");
+        Highlighter {
+            parser,
+            tree: None,
+            code: String::new(),
+            diagnostic: None,
+        }
+    }
+
+    fn update(&mut self, code: &str) {
+        if let Some(tree) = &mut self.tree {
+            if code == self.code {
+                return;
+            }
+            tree.edit(&diff(&self.code, code));
+        }
+        self.tree = self.parser.parse(code, self.tree.as_ref());
+        self.code = code.to_string();
+    }
+
+    /// The style of each byte of `code`: colors as set in `config.colors`,
+    /// and syntax errors underlined.
+    pub fn highlight(&mut self, lua_ctx: Context, code: &str) -> Vec<ContentStyle> {
+        self.update(code);
+        let mut styles = vec![ContentStyle::new(); code.len()];
+        let tree = match &self.tree {
+            Some(tree) => tree,
+            None => return styles,
+        };
+
+        let colors = lua_ctx
+            .globals()
+            .get::<_, rlua::Table>("config")
+            .and_then(|config| config.get::<_, Option<rlua::Table>>("colors"))
+            .ok()
+            .flatten();
+        let color = |kind: Kind| {
+            let name = colors
+                .as_ref()?
+                .get::<_, Option<String>>(kind.name())
+                .ok()??;
+            parse_color(&name)
+        };
+
+        let mut names = HashSet::new();
+        declared(tree.root_node(), code, &mut names);
+        let mut known = |name: &str| names.contains(name) || exists(lua_ctx, name);
+
+        let mut res = vec![];
+        spans(tree.root_node(), code, &mut known, &mut res);
+        for (start, stop, kind) in res {
+            for style in &mut styles[start..stop] {
+                style.foreground_color = color(kind);
+            }
+        }
+
+        let mut res = vec![];
+        errors(tree.root_node(), &mut res);
+        for node in res.iter().filter(|node| !unfinished(**node, code)) {
+            for style in &mut styles[node.byte_range()] {
+                style.attributes.set(Attribute::Underlined);
+            }
+        }
+        self.diagnostic = res.first().map(|node| {
+            let message = describe(*node, code);
+            match color(Kind::Error) {
+                Some(color) => message.with(color).to_string(),
+                None => message,
+            }
+        });
+
+        styles
+    }
+
+    pub fn diagnostic(&self) -> Option<&str> {
+        self.diagnostic.as_deref()
+    }
+}
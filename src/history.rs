@@ -0,0 +1,313 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::builtin::TableRes;
+
+/// A chunk submitted at the prompt.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Seconds since the epoch
+    pub time: u64,
+    pub cwd: String,
+    pub duration: Duration,
+    /// Whether the chunk ran without raising an error.
+    pub success: bool,
+    pub code: String,
+}
+impl Entry {
+    /// One line of the history file, fields separated by tabs.
+    fn serialize(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}",
+            self.time,
+            escape(&self.cwd),
+            self.duration.as_millis(),
+            self.success as u8,
+            escape(&self.code)
+        )
+    }
+
+    fn deserialize(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(5, '\t');
+        Some(Entry {
+            time: fields.next()?.parse().ok()?,
+            cwd: unescape(fields.next()?),
+            duration: Duration::from_millis(fields.next()?.parse().ok()?),
+            success: fields.next()? == "1",
+            code: unescape(fields.next()?),
+        })
+    }
+}
+
+/// Every entry, oldest first. Shared between the editor and the `history`
+/// builtin.
+static HISTORY: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+fn escape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+fn unescape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => res.push('\n'),
+                Some('r') => res.push('\r'),
+                Some('t') => res.push('\t'),
+                Some(c) => res.push(c),
+                None => {}
+            },
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+fn path() -> Option<PathBuf> {
+    home::home_dir().map(|mut p| {
+        p.push(".local/share/myshell/history");
+        p
+    })
+}
+
+/// Read the history file. Lines that can't be parsed are skipped.
+pub fn load() {
+    let entries = path()
+        .and_then(|p| fs::read_to_string(p).ok())
+        .map(|content| content.lines().filter_map(Entry::deserialize).collect())
+        .unwrap_or_default();
+    *HISTORY.lock().unwrap() = entries;
+}
+
+/// Record a chunk and append it to the history file.
+pub fn add(entry: Entry) -> io::Result<()> {
+    let line = entry.serialize();
+    HISTORY.lock().unwrap().push(entry);
+
+    let path = match path() {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{line}")
+}
+
+/// The code of every entry, oldest first.
+pub fn codes() -> Vec<String> {
+    HISTORY
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|entry| entry.code.clone())
+        .collect()
+}
+
+/// Length of the shortest part of `code` containing the characters of `query`
+/// in order, if any. Case insensitive unless `query` has uppercase letters.
+fn fuzzy_score(code: &str, query: &str) -> Option<usize> {
+    let fold = |c: char| match query.chars().any(char::is_uppercase) {
+        true => c,
+        false => c.to_lowercase().next().unwrap_or(c),
+    };
+    let code = code.chars().map(fold).collect::<Vec<_>>();
+    let query = query.chars().map(fold).collect::<Vec<_>>();
+    let first = match query.first() {
+        Some(first) => *first,
+        None => return Some(0),
+    };
+
+    (0..code.len())
+        .filter(|start| code[*start] == first)
+        .filter_map(|start| {
+            let mut pos = start;
+            for c in &query[1..] {
+                pos += 1 + code[pos + 1..].iter().position(|x| x == c)?;
+            }
+            Some(pos + 1 - start)
+        })
+        .min()
+}
+
+/// Entries fuzzy matching `query`, without duplicates, best match first and
+/// most recent first among equally good ones.
+pub fn search(query: &str) -> Vec<String> {
+    let mut matches = codes()
+        .into_iter()
+        .rev()
+        .enumerate()
+        .filter_map(|(age, code)| Some((fuzzy_score(&code, query)?, age, code)))
+        .collect::<Vec<_>>();
+    matches.sort();
+
+    let mut seen = HashSet::new();
+    matches
+        .into_iter()
+        .map(|(_, _, code)| code)
+        .filter(|code| seen.insert(code.clone()))
+        .collect()
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// `time` in the local timezone.
+fn format_time(time: u64) -> String {
+    let time = time as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let mut buf = [0u8; 32];
+    let len = unsafe {
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return time.to_string();
+        }
+        libc::strftime(
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+            c"%Y-%m-%d %H:%M:%S".as_ptr(),
+            &tm,
+        )
+    };
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
+pub fn list() -> TableRes {
+    TableRes {
+        header: vec![
+            "id".to_string(),
+            "time".to_string(),
+            "cwd".to_string(),
+            "duration".to_string(),
+            "success".to_string(),
+            "code".to_string(),
+        ],
+        entries: HISTORY
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                vec![
+                    (i + 1).to_string(),
+                    format_time(entry.time),
+                    entry.cwd.clone(),
+                    format!("{:.3}s", entry.duration.as_secs_f64()),
+                    entry.success.to_string(),
+                    entry.code.clone(),
+                ]
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_round_trip() {
+        let entry = Entry {
+            time: 1_700_000_000,
+            cwd: "/tmp/with\ttab".to_string(),
+            duration: Duration::from_millis(1234),
+            success: false,
+            code: "for i = 1, 2 do\n\tprint(\"a\\tb\\\\n\")\nend\\".to_string(),
+        };
+        let line = entry.serialize();
+        assert!(!line.contains('\n'));
+        assert_eq!(line.split('\t').count(), 5);
+
+        let parsed = Entry::deserialize(&line).unwrap();
+        assert_eq!(parsed.time, entry.time);
+        assert_eq!(parsed.cwd, entry.cwd);
+        assert_eq!(parsed.duration, entry.duration);
+        assert_eq!(parsed.success, entry.success);
+        assert_eq!(parsed.code, entry.code);
+    }
+
+    #[test]
+    fn escaping() {
+        for s in [
+            "",
+            "\\",
+            "\\n",
+            "a\nb",
+            "a\r\nb\r",
+            "\t\\\t",
+            "trailing\\",
+            "é\n✓",
+        ] {
+            assert_eq!(unescape(&escape(s)), s);
+        }
+        assert_eq!(escape("a\\nb\n"), "a\\\\nb\\n");
+        // `lines()` would take a raw `\r` at the end as part of the newline
+        assert!(!escape("x\r").contains('\r'));
+    }
+
+    #[test]
+    fn bad_lines_are_skipped() {
+        assert!(Entry::deserialize("").is_none());
+        assert!(Entry::deserialize("abc\t/\t0\t1\tls()").is_none());
+        assert!(Entry::deserialize("1\t/\t0").is_none());
+    }
+
+    #[test]
+    fn fuzzy_scores() {
+        assert_eq!(fuzzy_score("anything", ""), Some(0));
+        assert_eq!(fuzzy_score("ls()", "ls"), Some(2));
+        assert_eq!(fuzzy_score("cd('/tmp')", "ct"), Some(6));
+        assert_eq!(fuzzy_score("ls()", "sl"), None);
+        // The shortest span wins
+        assert_eq!(fuzzy_score("l x s ls", "ls"), Some(2));
+        // Case insensitive unless the query has uppercase letters
+        assert_eq!(fuzzy_score("Git.Status", "gs"), Some(5));
+        assert_eq!(fuzzy_score("git.status", "GS"), None);
+    }
+
+    #[test]
+    fn search_ranking() {
+        let entry = |code: &str| Entry {
+            time: 0,
+            cwd: String::new(),
+            duration: Duration::ZERO,
+            success: true,
+            code: code.to_string(),
+        };
+        *HISTORY.lock().unwrap() = ["git status", "l x s", "ls()", "git status", "cat log"]
+            .into_iter()
+            .map(entry)
+            .collect();
+
+        // Tightest match first, then most recent, without duplicates
+        assert_eq!(search("ls"), ["ls()", "l x s"]);
+        assert_eq!(search("s"), ["git status", "ls()", "l x s"]);
+        assert_eq!(search("st"), ["git status"]);
+        assert_eq!(search("zz"), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,67 @@
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
+
+/// Events read while something else was running, kept for the editor.
+static PENDING: Mutex<VecDeque<Event>> = Mutex::new(VecDeque::new());
+
+pub fn poll(timeout: Duration) -> crossterm::Result<bool> {
+    if !PENDING.lock().unwrap().is_empty() {
+        return Ok(true);
+    }
+    event::poll(timeout)
+}
+
+pub fn read() -> crossterm::Result<Event> {
+    if let Some(event) = PENDING.lock().unwrap().pop_front() {
+        return Ok(event);
+    }
+    event::read()
+}
+
+pub fn is_interrupt(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::Key(KeyEvent {
+            code: KeyCode::Char('c'),
+            modifiers: KeyModifiers::CONTROL,
+        })
+    )
+}
+
+fn is_suspend(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::Key(KeyEvent {
+            code: KeyCode::Char('z'),
+            modifiers: KeyModifiers::CONTROL,
+        })
+    )
+}
+
+/// The signal asked for since last time, without blocking: `SIGINT` for
+/// Ctrl-C, or else `SIGTSTP` for Ctrl-Z. In raw mode the terminal doesn't
+/// send them, so they have to be looked for in the input. Anything else typed
+/// meanwhile is kept for the editor.
+pub fn signal() -> Option<i32> {
+    let mut res = None;
+
+    while let Ok(true) = event::poll(Duration::ZERO) {
+        match event::read() {
+            Ok(event) if is_interrupt(&event) => res = Some(libc::SIGINT),
+            Ok(event) if is_suspend(&event) => {
+                res = res.or(Some(libc::SIGTSTP));
+            }
+            Ok(event) => PENDING.lock().unwrap().push_back(event),
+            Err(_) => break,
+        }
+    }
+
+    res
+}
+
+/// Whether Ctrl-C was pressed since last time. Ctrl-Z is dropped, Lua code
+/// can't be suspended.
+pub fn interrupted() -> bool {
+    signal() == Some(libc::SIGINT)
+}
@@ -0,0 +1,382 @@
+use std::{io, os::unix::process::CommandExt, process, sync::Mutex};
+
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
+
+use crate::builtin::TableRes;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Running,
+    Stopped,
+    Done(Option<i32>),
+}
+impl State {
+    fn name(&self) -> String {
+        match self {
+            State::Running => "running".to_string(),
+            State::Stopped => "stopped".to_string(),
+            State::Done(Some(code)) => format!("done ({code})"),
+            State::Done(None) => "killed".to_string(),
+        }
+    }
+}
+
+/// A pipeline running in its own process group, either in the background or
+/// stopped with Ctrl-Z.
+#[derive(Debug, Clone)]
+struct Job {
+    id: usize,
+    pgid: i32,
+    /// Processes of the group that haven't exited yet, the last stage last.
+    pids: Vec<i32>,
+    last_pid: i32,
+    command: String,
+    state: State,
+}
+
+/// Jobs are tied to the process, like process groups and signal dispositions.
+static JOBS: Mutex<Vec<Job>> = Mutex::new(Vec::new());
+
+/// How a foreground pipeline gave the terminal back.
+pub enum Wait {
+    Exited(Option<i32>),
+    Stopped(usize),
+}
+
+fn terminal() -> Option<i32> {
+    match unsafe { libc::isatty(libc::STDIN_FILENO) } {
+        1 => Some(libc::STDIN_FILENO),
+        _ => None,
+    }
+}
+
+fn give_terminal(pgid: i32) {
+    if let Some(fd) = terminal() {
+        unsafe { libc::tcsetpgrp(fd, pgid) };
+    }
+}
+
+fn take_terminal() {
+    give_terminal(unsafe { libc::getpgrp() });
+}
+
+/// Set up the shell for job control: it must survive handing the terminal
+/// over to other process groups and taking it back.
+pub fn init() {
+    unsafe {
+        libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+        libc::signal(libc::SIGTTIN, libc::SIG_IGN);
+        libc::signal(libc::SIGTSTP, libc::SIG_IGN);
+    }
+}
+
+/// Make the process spawned by `cmd` join the process group `pgid` (a new one
+/// when `0`), grabbing the terminal for it when running in the foreground.
+pub fn prepare(cmd: &mut process::Command, pgid: i32, foreground: bool) {
+    unsafe {
+        cmd.pre_exec(move || {
+            libc::setpgid(0, pgid);
+            if foreground && libc::isatty(libc::STDIN_FILENO) == 1 {
+                libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpgrp());
+            }
+            // Ignored signals stay ignored across exec
+            libc::signal(libc::SIGTTOU, libc::SIG_DFL);
+            libc::signal(libc::SIGTTIN, libc::SIG_DFL);
+            libc::signal(libc::SIGTSTP, libc::SIG_DFL);
+            Ok(())
+        });
+    }
+}
+
+/// Also done from the shell side, so the group exists whichever process gets
+/// scheduled first.
+pub fn set_group(pid: i32, pgid: i32) {
+    unsafe { libc::setpgid(pid, pgid) };
+}
+
+fn exit_code(status: i32) -> Option<i32> {
+    match libc::WIFEXITED(status) {
+        true => Some(libc::WEXITSTATUS(status)),
+        false => None,
+    }
+}
+
+/// Wait for `pids`, all in the process group `pgid`, until they exit or one of
+/// them is stopped. Returns the processes still alive if stopped, and the exit
+/// code of `last_pid` otherwise.
+fn wait_group(
+    pgid: i32,
+    mut pids: Vec<i32>,
+    last_pid: i32,
+) -> io::Result<(Vec<i32>, bool, Option<i32>)> {
+    let mut code = None;
+
+    while !pids.is_empty() {
+        let mut status = 0;
+        let pid = unsafe { libc::waitpid(-pgid, &mut status, libc::WUNTRACED) };
+        if pid < 0 {
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::Interrupted => continue,
+                _ => return Err(err),
+            }
+        }
+
+        if libc::WIFSTOPPED(status) {
+            return Ok((pids, true, code));
+        }
+
+        pids.retain(|p| *p != pid);
+        if pid == last_pid {
+            code = exit_code(status);
+        }
+    }
+
+    Ok((pids, false, code))
+}
+
+fn add(pgid: i32, pids: Vec<i32>, last_pid: i32, command: String, state: State) -> usize {
+    let mut jobs = JOBS.lock().unwrap();
+    let id = jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+    jobs.push(Job {
+        id,
+        pgid,
+        pids,
+        last_pid,
+        command,
+        state,
+    });
+    id
+}
+
+/// Hand the terminal to the pipeline made of `pids` and wait for it. On
+/// Ctrl-Z it is registered as a stopped job and the shell takes over again.
+pub fn foreground(pgid: i32, pids: Vec<i32>, command: String) -> io::Result<Wait> {
+    let last_pid = match pids.last() {
+        Some(pid) => *pid,
+        None => return Ok(Wait::Exited(None)),
+    };
+
+    give_terminal(pgid);
+    let res = wait_group(pgid, pids, last_pid);
+    take_terminal();
+
+    let (pids, stopped, code) = res?;
+    match stopped {
+        true => Ok(Wait::Stopped(add(
+            pgid,
+            pids,
+            last_pid,
+            command,
+            State::Stopped,
+        ))),
+        false => Ok(Wait::Exited(code)),
+    }
+}
+
+/// Register a pipeline spawned in the background.
+pub fn background(pgid: i32, pids: Vec<i32>, command: String) -> usize {
+    let last_pid = pids.last().copied().unwrap_or(pgid);
+    add(pgid, pids, last_pid, command, State::Running)
+}
+
+/// Register a pipeline stopped with Ctrl-Z while it wasn't in the foreground.
+pub fn stopped(pgid: i32, pids: Vec<i32>, command: String) -> usize {
+    let last_pid = pids.last().copied().unwrap_or(pgid);
+    add(pgid, pids, last_pid, command, State::Stopped)
+}
+
+/// Collect the status of every job without blocking, returning a line
+/// describing each state change.
+fn poll(jobs: &mut [Job]) -> Vec<String> {
+    let mut res = vec![];
+
+    for job in jobs.iter_mut() {
+        let previous = job.state;
+
+        for pid in job.pids.clone() {
+            let mut status = 0;
+            let flags = libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED;
+            let ret = unsafe { libc::waitpid(pid, &mut status, flags) };
+
+            if ret == 0 {
+                continue;
+            } else if ret < 0 {
+                // Already reaped somewhere else
+                job.pids.retain(|p| *p != pid);
+            } else if libc::WIFSTOPPED(status) {
+                job.state = State::Stopped;
+            } else if libc::WIFCONTINUED(status) {
+                job.state = State::Running;
+            } else {
+                job.pids.retain(|p| *p != pid);
+                if pid == job.last_pid {
+                    job.state = State::Done(exit_code(status));
+                }
+            }
+        }
+
+        if job.pids.is_empty() {
+            if let State::Running | State::Stopped = job.state {
+                job.state = State::Done(None);
+            }
+        }
+
+        if job.state != previous {
+            res.push(format!(
+                "[{}] {}\t{}",
+                job.id,
+                job.state.name(),
+                job.command
+            ));
+        }
+    }
+
+    res
+}
+
+/// Poll every job and forget the finished ones, returning the notifications
+/// to show the user.
+pub fn update() -> Vec<String> {
+    let mut jobs = JOBS.lock().unwrap();
+    let res = poll(&mut jobs);
+    jobs.retain(|job| !matches!(job.state, State::Done(_)));
+    res
+}
+
+/// Every job, including the ones that finished since last time.
+pub fn list() -> TableRes {
+    let mut jobs = JOBS.lock().unwrap();
+    poll(&mut jobs);
+
+    let table = TableRes {
+        header: vec![
+            "id".to_string(),
+            "pgid".to_string(),
+            "status".to_string(),
+            "command".to_string(),
+        ],
+        entries: jobs
+            .iter()
+            .map(|job| {
+                vec![
+                    job.id.to_string(),
+                    job.pgid.to_string(),
+                    job.state.name(),
+                    job.command.clone(),
+                ]
+            })
+            .collect(),
+    };
+
+    jobs.retain(|job| !matches!(job.state, State::Done(_)));
+
+    table
+}
+
+/// The job with the given id, or the most recent one.
+fn find(id: Option<usize>) -> io::Result<Job> {
+    let jobs = JOBS.lock().unwrap();
+    match id {
+        Some(id) => jobs.iter().find(|job| job.id == id),
+        None => jobs.last(),
+    }
+    .cloned()
+    .ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            match id {
+                Some(id) => format!("no such job: {id}"),
+                None => "no current job".to_string(),
+            },
+        )
+    })
+}
+
+fn remove(id: usize) {
+    JOBS.lock().unwrap().retain(|job| job.id != id);
+}
+
+fn set_state(id: usize, state: State) {
+    if let Some(job) = JOBS.lock().unwrap().iter_mut().find(|job| job.id == id) {
+        job.state = state;
+    }
+}
+
+/// Resume a job in the foreground and wait for it, like any other command run
+/// on the terminal.
+pub fn fg(id: Option<usize>) -> io::Result<Wait> {
+    let job = find(id)?;
+    remove(job.id);
+
+    disable_raw_mode().map_err(|e| io::Error::other(e.to_string()))?;
+    give_terminal(job.pgid);
+    unsafe { libc::killpg(job.pgid, libc::SIGCONT) };
+    let res = wait_group(job.pgid, job.pids.clone(), job.last_pid);
+    take_terminal();
+    enable_raw_mode().map_err(|e| io::Error::other(e.to_string()))?;
+
+    let (pids, stopped, code) = res?;
+    match stopped {
+        true => {
+            let mut jobs = JOBS.lock().unwrap();
+            jobs.push(Job {
+                pids,
+                state: State::Stopped,
+                ..job
+            });
+            jobs.sort_by_key(|job| job.id);
+            Ok(Wait::Stopped(job.id))
+        }
+        false => Ok(Wait::Exited(code)),
+    }
+}
+
+/// Resume a stopped job in the background.
+pub fn bg(id: Option<usize>) -> io::Result<usize> {
+    let job = find(id)?;
+    if unsafe { libc::killpg(job.pgid, libc::SIGCONT) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    set_state(job.id, State::Running);
+    Ok(job.id)
+}
+
+pub fn signal_number(name: &str) -> Option<i32> {
+    let name = name.trim_start_matches("SIG");
+    Some(match name {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "TERM" => libc::SIGTERM,
+        "CONT" => libc::SIGCONT,
+        "STOP" => libc::SIGSTOP,
+        "TSTP" => libc::SIGTSTP,
+        _ => return name.parse().ok(),
+    })
+}
+
+/// Forward Ctrl-C to a process group running without the terminal.
+pub fn interrupt(pgid: i32) {
+    unsafe { libc::killpg(pgid, libc::SIGINT) };
+}
+
+/// Forward Ctrl-Z to a process group running without the terminal.
+pub fn suspend(pgid: i32) {
+    unsafe { libc::killpg(pgid, libc::SIGTSTP) };
+}
+
+/// Send `signal` to every process of a job.
+pub fn kill(id: Option<usize>, signal: i32) -> io::Result<()> {
+    let job = find(id)?;
+    if unsafe { libc::killpg(job.pgid, signal) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if signal == libc::SIGCONT {
+        set_state(job.id, State::Running);
+    }
+    Ok(())
+}
@@ -1,23 +1,30 @@
 mod builtin;
+mod complete;
+mod editor;
+mod exec;
+mod highlight;
+mod history;
+mod input;
+mod jobs;
+mod vi;
 
 use std::{
-    fs,
+    env, fs,
     io::{stdout, Write},
-    process::{self, Stdio},
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use builtin::TableRes;
 use crossterm::{
-    cursor::{position, EnableBlinking, MoveTo, MoveToNextLine, Show},
-    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
+    cursor::{position, EnableBlinking, MoveToNextLine},
+    event::{Event, KeyCode, KeyEvent, KeyModifiers},
     queue,
     style::Print,
-    terminal::{disable_raw_mode, enable_raw_mode, size, Clear, ClearType, ScrollUp},
+    terminal::{disable_raw_mode, enable_raw_mode, size, ScrollUp},
 };
-use is_executable::IsExecutable;
-use rlua::{Lua, ToLua, Variadic};
+use editor::Command;
+use rlua::{Lua, Variadic};
 
 fn print(s: &str) -> BoxedRes<()> {
     let mut stdout = stdout();
@@ -46,206 +53,91 @@ fn print(s: &str) -> BoxedRes<()> {
 
 type BoxedRes<T> = Result<T, Box<dyn std::error::Error>>;
 
-struct Command {
-    cmd: Vec<String>,
-    cursor_initial: (u16, u16),
-    cursor: (usize, usize),
-    redraw: bool,
-}
-impl Command {
-    fn new(lua: &Lua) -> Self {
-        let ps1 = lua
-            .context(|lua_ctx| {
-                let globals = lua_ctx.globals();
-                let config = globals.get::<_, rlua::Table>("config")?;
-                let ps1 = config.get::<_, rlua::Function>("ps1")?;
-                ps1.call::<_, String>(())
-            })
-            .unwrap();
-        print(&ps1).unwrap();
-
-        Command {
-            cmd: vec![String::new()],
-            cursor_initial: position().unwrap(),
-            cursor: (0, 0),
-            redraw: true,
+/// Number of Lua instructions between two runs of the limits hook.
+const HOOK_INTERVAL: u32 = 10_000;
+
+/// Install the safety nets for the next chunk: Ctrl-C raises an error in the
+/// running Lua code, `config.max_instructions` caps how long it may run and
+/// `config.max_memory` how many bytes the whole Lua state may use.
+fn set_limits(lua: &Lua) -> BoxedRes<()> {
+    let limits = lua.context(|lua_ctx| -> rlua::Result<_> {
+        Ok(
+            match lua_ctx.globals().get::<_, Option<rlua::Table>>("config")? {
+                Some(config) => (
+                    config.get::<_, Option<u64>>("max_instructions")?,
+                    config.get::<_, Option<usize>>("max_memory")?,
+                ),
+                None => (None, None),
+            },
+        )
+    });
+    // Like `config.max_memory = "1G"`, which shouldn't take the shell down
+    let (max_instructions, max_memory) = match limits {
+        Ok(limits) => limits,
+        Err(e) => {
+            print(&format!(
+                "bad config.max_instructions or config.max_memory, running without limits: {e}\n"
+            ))?;
+            (None, None)
         }
-    }
-
-    fn new_from(old: Self, lua: &Lua) -> Self {
-        let mut command = Command::new(lua);
-        command.cmd = old.cmd;
-        command.cursor = old.cursor;
-        command.redraw = true;
-        command
-    }
-
-    fn draw(&mut self) -> BoxedRes<()> {
-        if self.redraw {
-            let mut stdout = stdout();
-            queue!(
-                stdout,
-                MoveTo(self.cursor_initial.0, self.cursor_initial.1),
-                Clear(ClearType::FromCursorDown),
-            )?;
-
-            let mut after_ps1 = position()?;
-
-            let term_height = size()?.1;
-            let cursor_height = self.cursor_initial.1;
-            let available_space = term_height - cursor_height;
-
-            for (i, l) in self.cmd.iter().enumerate() {
-                if i >= available_space as usize {
-                    queue!(stdout, ScrollUp(1))?;
-                    self.cursor_initial.1 -= 1;
-                    after_ps1.1 -= 1;
-                }
-
-                if i > 0 {
-                    queue!(stdout, MoveToNextLine(1))?;
+    };
+
+    lua.set_memory_limit(max_memory);
+
+    let mut executed = 0;
+    let mut last_check = Instant::now();
+    let mut interrupted = false;
+    lua.set_hook(
+        rlua::HookTriggers {
+            every_nth_instruction: Some(HOOK_INTERVAL),
+            ..Default::default()
+        },
+        move |_, _| {
+            executed += HOOK_INTERVAL as u64;
+            if let Some(max) = max_instructions {
+                if executed > max {
+                    return Err(rlua::Error::RuntimeError(format!(
+                        "instruction limit exceeded ({max})"
+                    )));
                 }
-                queue!(stdout, Print(l)).unwrap();
-            }
-
-            if self.cursor.1 == 0 {
-                queue!(
-                    stdout,
-                    MoveTo(after_ps1.0 + self.cursor.0 as u16, after_ps1.1),
-                )?;
-            } else {
-                queue!(
-                    stdout,
-                    MoveTo(self.cursor.0 as u16, after_ps1.1 + self.cursor.1 as u16),
-                )?;
-            }
-
-            queue!(stdout, Show)?;
-
-            stdout.flush()?;
-
-            self.redraw = false;
-        }
-
-        Ok(())
-    }
-
-    fn code(&self) -> String {
-        self.cmd.join("\n")
-    }
-
-    fn add_char(&mut self, c: char) {
-        match c {
-            '\r' => {}
-            '\n' => {
-                let (line, new_line) = self.cmd[self.cursor.1].split_at(self.cursor.0);
-                let new_line = new_line.to_string();
-                let line = line.to_string();
-                self.cmd[self.cursor.1] = line;
-                self.cursor = (0, self.cursor.1 + 1);
-                self.cmd.insert(self.cursor.1, new_line);
             }
-            c => {
-                self.cmd[self.cursor.1].insert(self.cursor.0, c);
-                self.cursor.0 += 1;
-            }
-        }
-
-        self.redraw = true;
-    }
-
-    fn remove_char(&mut self) {
-        match self.cursor.0 {
-            0 if self.cursor.1 > 0 => {
-                let line = self.cmd.remove(self.cursor.1);
-                self.cursor.1 -= 1;
-                self.cursor.0 = self.cmd[self.cursor.1].len();
-                self.cmd[self.cursor.1].extend(line.chars());
 
-                self.redraw = true;
-            }
-            0 => {
-                // Nothing
+            // Polling the terminal is a syscall, don't do it on every call.
+            // Once interrupted, stay interrupted so `pcall` can't swallow it
+            if !interrupted && last_check.elapsed() >= Duration::from_millis(50) {
+                last_check = Instant::now();
+                interrupted = input::interrupted();
             }
-            x => {
-                self.cmd[self.cursor.1].remove(x - 1);
-                self.cursor.0 -= 1;
-
-                self.redraw = true;
+            match interrupted {
+                true => Err(rlua::Error::RuntimeError("interrupted".to_string())),
+                false => Ok(()),
             }
-        }
-    }
+        },
+    );
 
-    fn left(&mut self) -> bool {
-        match self.cursor.0 {
-            0 => {
-                // Nothing
-                false
-            }
-            _ => {
-                self.cursor.0 -= 1;
-                self.redraw = true;
-                true
-            }
-        }
-    }
-
-    fn right(&mut self, wrapping: bool) -> bool {
-        match self.cursor.0 {
-            x if x == self.cmd[self.cursor.1].len() => {
-                if wrapping {
-                    if self.down() {
-                        self.cursor.0 = 0;
-                        true
-                    } else {
-                        false
-                    }
-                } else {
-                    // Nothing
-                    false
-                }
-            }
-            _ => {
-                self.cursor.0 += 1;
-                self.redraw = true;
-                true
-            }
-        }
-    }
+    Ok(())
+}
 
-    fn up(&mut self) -> bool {
-        match self.cursor.0 {
-            0 => {
-                // Nothing
-                false
-            }
-            _ => {
-                self.cursor.1 -= 1;
-                self.cursor.0 = self.cursor.0.min(self.cmd[self.cursor.1].len());
-                self.redraw = true;
-                true
-            }
-        }
-    }
+fn clear_limits(lua: &Lua) {
+    lua.remove_hook();
+    lua.set_memory_limit(None);
+}
 
-    fn down(&mut self) -> bool {
-        match self.cursor.1 {
-            x if x < self.cmd.len() - 1 => {
-                self.cursor.1 += 1;
-                self.cursor.0 = self.cursor.0.min(self.cmd[self.cursor.1].len());
-                self.redraw = true;
-                true
-            }
-            _ => {
-                //Nothing
-                false
-            }
+/// The message of an error raised from Lua, with the error returned by Rust
+/// callbacks instead of only their traceback.
+fn error_message(err: &(dyn std::error::Error + 'static)) -> String {
+    match err.downcast_ref::<rlua::Error>() {
+        Some(rlua::Error::CallbackError { traceback, cause }) => {
+            format!("{}\n{traceback}", error_message(cause.as_ref()))
         }
+        _ => err.to_string(),
     }
 }
 
 fn main() -> BoxedRes<()> {
     enable_raw_mode()?;
+    jobs::init();
+    history::load();
 
     let query =
         tree_sitter::Query::new(tree_sitter_lua::language(), "(assignment_statement)").unwrap();
@@ -254,81 +146,6 @@ fn main() -> BoxedRes<()> {
 
     let should_tty = Arc::new(Mutex::new(false));
 
-    for path in env!("PATH").split(':') {
-        if let Ok(entries) = fs::read_dir(path) {
-            for entry in entries {
-                if let Ok(entry) = entry {
-                    if let Some(name) = entry.file_name().to_str() {
-                        let path = entry.path();
-                        if path.is_executable() {
-                            let should_tty = Arc::clone(&should_tty);
-                            let lua_res: BoxedRes<()> = lua.context(move |lua_ctx| {
-                                let path = path;
-                                let globals = lua_ctx.globals();
-
-                                let call_fn = lua_ctx.create_function(
-                                    move |lua_ctx, args: Variadic<String>| {
-                                        let path = path.clone();
-
-                                        let mut cmd = process::Command::new(&path);
-                                        cmd.args(args.iter().collect::<Vec<_>>());
-
-                                        let should_tty_lock = *should_tty.lock().unwrap();
-
-                                        if should_tty_lock {
-                                            disable_raw_mode().unwrap();
-                                            cmd.stdout(Stdio::inherit()).stderr(Stdio::inherit());
-                                        } else {
-                                            cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
-                                        }
-
-                                        let output =
-                                            cmd.spawn().unwrap().wait_with_output().unwrap();
-
-                                        match should_tty_lock {
-                                            true => {
-                                                enable_raw_mode().unwrap();
-                                                *should_tty.lock().unwrap() = false;
-                                                Ok(rlua::Value::Nil)
-                                            }
-                                            false => {
-                                                let table = lua_ctx.create_table()?;
-                                                table.set("code", output.status.code())?;
-                                                table.set(
-                                                    "path",
-                                                    path.to_str().unwrap().to_string(),
-                                                )?;
-                                                table.set(
-                                                    "stdout",
-                                                    std::str::from_utf8(&output.stdout)
-                                                        .unwrap()
-                                                        .trim()
-                                                        .to_string(),
-                                                )?;
-                                                table.set(
-                                                    "stderr",
-                                                    std::str::from_utf8(&output.stderr)
-                                                        .unwrap()
-                                                        .trim()
-                                                        .to_string(),
-                                                )?;
-                                                table.to_lua(lua_ctx)
-                                            }
-                                        }
-                                    },
-                                )?;
-                                globals.set(name, call_fn)?;
-
-                                Ok(())
-                            });
-                            lua_res?;
-                        }
-                    }
-                }
-            }
-        }
-    }
-
     lua.context::<_, BoxedRes<()>>(|lua_ctx| {
         let globals = lua_ctx.globals();
 
@@ -340,11 +157,101 @@ fn main() -> BoxedRes<()> {
 
         let cd = lua_ctx.create_function(|_, path: Variadic<String>| {
             let path = path.first().map(|v| v as &str).unwrap_or_else(|| "");
-            Ok(builtin::cd(path))
+            builtin::cd(path);
+            Ok(())
         })?;
         globals.set("cd", cd)?;
 
-        //let pipe = lua_ctx.create_function(|lua_ctx, |)
+        let rehash = lua_ctx.create_function(|lua_ctx, ()| exec::rehash(lua_ctx))?;
+        globals.set("rehash", rehash)?;
+
+        let env_table = lua_ctx.create_table()?;
+        let env_meta = lua_ctx.create_table()?;
+        env_meta.set(
+            "__index",
+            lua_ctx.create_function(|_, (_, key): (rlua::Table, String)| Ok(env::var(key).ok()))?,
+        )?;
+        env_meta.set(
+            "__newindex",
+            lua_ctx.create_function(
+                |lua_ctx, (_, key, value): (rlua::Table, String, Option<String>)| {
+                    match value {
+                        Some(value) => env::set_var(&key, value),
+                        None => env::remove_var(&key),
+                    }
+                    if key == "PATH" {
+                        exec::rehash(lua_ctx)?;
+                    }
+                    Ok(())
+                },
+            )?,
+        )?;
+        env_table.set_metatable(Some(env_meta));
+        globals.set("env", env_table)?;
+
+        let pipe_should_tty = Arc::clone(&should_tty);
+        let pipe = lua_ctx.create_function(move |lua_ctx, stages: Variadic<rlua::Value>| {
+            exec::pipe(lua_ctx, stages.into_iter().collect(), &pipe_should_tty)
+        })?;
+        globals.set("pipe", pipe)?;
+
+        let tty =
+            lua_ctx.create_function(|lua_ctx, (f, args): (rlua::Function, rlua::MultiValue)| {
+                exec::with_mode(lua_ctx, "tty", f, args)
+            })?;
+        globals.set("tty", tty)?;
+
+        let capture =
+            lua_ctx.create_function(|lua_ctx, (f, args): (rlua::Function, rlua::MultiValue)| {
+                exec::with_mode(lua_ctx, "capture", f, args)
+            })?;
+        globals.set("capture", capture)?;
+
+        let jobs = lua_ctx.create_function(|_, ()| Ok(jobs::list()))?;
+        globals.set("jobs", jobs)?;
+
+        let fg = lua_ctx.create_function(|_, id: Option<usize>| {
+            match jobs::fg(id).map_err(rlua::Error::external)? {
+                jobs::Wait::Exited(code) => Ok(code),
+                jobs::Wait::Stopped(id) => {
+                    print(&format!("\n[{id}] stopped\n")).unwrap();
+                    Ok(None)
+                }
+            }
+        })?;
+        globals.set("fg", fg)?;
+
+        let bg_should_tty = Arc::clone(&should_tty);
+        let bg = lua_ctx.create_function(move |lua_ctx, job: rlua::Value| match job {
+            rlua::Value::Nil => jobs::bg(None).map_err(rlua::Error::external),
+            rlua::Value::Integer(id) => jobs::bg(Some(id as usize)).map_err(rlua::Error::external),
+            cmd => exec::spawn_background(lua_ctx, cmd, &bg_should_tty),
+        })?;
+        globals.set("bg", bg)?;
+
+        let kill =
+            lua_ctx.create_function(|_, (id, signal): (Option<usize>, Option<String>)| {
+                let signal = match signal {
+                    Some(signal) => jobs::signal_number(&signal).ok_or_else(|| {
+                        rlua::Error::RuntimeError(format!("unknown signal: {signal}"))
+                    })?,
+                    None => libc::SIGTERM,
+                };
+                jobs::kill(id, signal).map_err(rlua::Error::external)
+            })?;
+        globals.set("kill", kill)?;
+
+        let history = lua_ctx.create_function(|_, ()| Ok(history::list()))?;
+        globals.set("history", history)?;
+
+        let complete =
+            lua_ctx.create_function(|lua_ctx, (name, f): (String, Option<rlua::Function>)| {
+                complete::register(lua_ctx, name, f)
+            })?;
+        globals.set("complete", complete)?;
+
+        let vi_mode = lua_ctx.create_function(|_, ()| Ok(vi::mode_name()))?;
+        globals.set("vi_mode", vi_mode)?;
 
         let print = lua_ctx.create_function(|_, s: String| {
             print(&s).unwrap();
@@ -370,12 +277,41 @@ fn main() -> BoxedRes<()> {
                     end
                 end
 
-                config = { ps1 = function() return "$ " end }
+                function x(name, ...)
+                    local fn = cmd[name]
+                    if fn == nil then
+                        error("command not found: " .. name, 2)
+                    end
+                    return fn(...)
+                end
+
+                config = {
+                    ps1 = function() return "$ " end,
+                    colors = {
+                        keyword = "magenta",
+                        string = "green",
+                        number = "yellow",
+                        comment = "dark_grey",
+                        call = "blue",
+                        unknown_call = "red",
+                        error = "dark_red",
+                    },
+                    -- "emacs" or "vi", vi_mode() then telling the mode to ps1
+                    edit_mode = "emacs",
+                    -- max_instructions = 1e9,
+                    -- max_memory = 512 * 1024 * 1024,
+                    interactive = {
+                        "vi", "vim", "nvim", "nano", "emacs", "less", "more", "man",
+                        "top", "htop", "ssh", "tmux", "screen",
+                    },
+                }
                 "#,
             )
             .exec()
             .unwrap();
 
+        exec::install(lua_ctx, Arc::clone(&should_tty))?;
+
         Ok(())
     })?;
 
@@ -384,25 +320,84 @@ fn main() -> BoxedRes<()> {
         p
     });
     if let Some(init_code) = init_code_path.and_then(|p| fs::read_to_string(p).ok()) {
-        lua.context::<_, BoxedRes<()>>(|lua_ctx| {
+        set_limits(&lua)?;
+        let res = lua.context::<_, BoxedRes<()>>(|lua_ctx| {
             lua_ctx.load(&init_code).exec()?;
             Ok(())
-        })?;
+        });
+        clear_limits(&lua);
+        res?;
     }
 
+    builtin::set_table_width(size()?.0 as usize);
     let mut cmd = Command::new(&lua);
 
     loop {
-        cmd.draw()?;
+        cmd.draw(&lua)?;
 
-        if event::poll(Duration::from_millis(100))? {
-            match event::read()? {
-                Event::Key(KeyEvent { code, modifiers }) => match (code, modifiers) {
+        if input::poll(Duration::from_millis(100))? {
+            let event = input::read()?;
+            if let Event::Resize(width, _) = event {
+                builtin::set_table_width(width as usize);
+                cmd.resize()?;
+            }
+            if let Event::Key(KeyEvent { code, modifiers }) = event {
+                cmd.clear_message();
+                // Alt-Y only replaces what the key just before yanked
+                if !matches!(
+                    (code, modifiers),
+                    (
+                        KeyCode::Char('y'),
+                        KeyModifiers::CONTROL | KeyModifiers::ALT
+                    )
+                ) {
+                    cmd.forget_yank();
+                }
+                match (code, modifiers) {
+                    _ if cmd.search_key(code, modifiers) => {}
+                    _ if cmd.menu_key(code, modifiers) => {}
+                    _ if cmd.vi_key(code, modifiers) => {}
                     (KeyCode::Char('d'), KeyModifiers::CONTROL) => {
                         break;
                     }
+                    (KeyCode::Char('c'), KeyModifiers::CONTROL) => {
+                        cmd.finish(&lua)?;
+                        print("^C\n")?;
+                        cmd = Command::new(&lua);
+                    }
                     (KeyCode::Backspace, m) if m.is_empty() => cmd.remove_char(),
-                    (KeyCode::Char(' '), KeyModifiers::CONTROL) => {
+                    (KeyCode::Char('a'), KeyModifiers::CONTROL) | (KeyCode::Home, _) => {
+                        cmd.line_start()
+                    }
+                    (KeyCode::Char('e'), KeyModifiers::CONTROL) | (KeyCode::End, _) => {
+                        cmd.line_end()
+                    }
+                    (KeyCode::Char('b'), KeyModifiers::CONTROL) => {
+                        cmd.left();
+                    }
+                    (KeyCode::Char('f'), KeyModifiers::CONTROL) => {
+                        cmd.right(false);
+                    }
+                    (KeyCode::Char('b'), KeyModifiers::ALT) => cmd.word_left(),
+                    (KeyCode::Char('f'), KeyModifiers::ALT) => cmd.word_right(),
+                    (KeyCode::Char('w'), KeyModifiers::CONTROL) => cmd.kill_word_left(),
+                    (KeyCode::Char('d'), KeyModifiers::ALT) => cmd.kill_word_right(),
+                    (KeyCode::Char('k'), KeyModifiers::CONTROL) => cmd.kill_line_end(),
+                    (KeyCode::Char('u'), KeyModifiers::CONTROL) => cmd.kill_line_start(),
+                    (KeyCode::Char('y'), KeyModifiers::CONTROL) => cmd.yank(),
+                    (KeyCode::Char('y'), KeyModifiers::ALT) => cmd.yank_pop(),
+                    (KeyCode::Char('l'), KeyModifiers::CONTROL) => cmd.clear_screen()?,
+                    (KeyCode::Char('r'), KeyModifiers::CONTROL) => cmd.start_search(),
+                    (KeyCode::Tab, m) if m.is_empty() => cmd.complete(&lua, false)?,
+                    (KeyCode::BackTab, _) => cmd.complete(&lua, true)?,
+                    (KeyCode::Enter, m) if m.is_empty() && !cmd.is_complete(&lua) => cmd.newline(),
+                    // Force a new line
+                    (KeyCode::Enter, KeyModifiers::ALT)
+                    | (KeyCode::Char('j'), KeyModifiers::CONTROL) => cmd.newline(),
+                    // Enter on a complete chunk, or force its execution
+                    (KeyCode::Char(' '), KeyModifiers::CONTROL)
+                    | (KeyCode::Enter, KeyModifiers::NONE) => {
+                        cmd.finish(&lua)?;
                         print("\n")?;
 
                         let code = cmd.code();
@@ -416,27 +411,29 @@ fn main() -> BoxedRes<()> {
 
                         let print_tty = if node.child_count() <= 1 {
                             let mut query_cursor = tree_sitter::QueryCursor::new();
-                            if query_cursor
+                            query_cursor
                                 .matches(&query, tree.root_node(), |_| "")
                                 .count()
                                 == 0
-                            {
-                                true
-                            } else {
-                                false
-                            }
                         } else {
                             false
                         };
 
                         *should_tty.lock().unwrap() = print_tty;
 
-                        match lua.context::<_, BoxedRes<String>>(|lua_ctx| {
+                        let time = history::now();
+                        let cwd = env::current_dir()
+                            .map(|p| p.to_string_lossy().to_string())
+                            .unwrap_or_default();
+                        let start = Instant::now();
+
+                        set_limits(&lua)?;
+                        let res = lua.context::<_, BoxedRes<String>>(|lua_ctx| {
                             Ok(match lua_ctx.load(&code).eval::<rlua::Value>()? {
                                 rlua::Value::UserData(data) => match data.borrow::<TableRes>() {
                                     Ok(table) => {
                                         disable_raw_mode()?;
-                                        table.as_display_table().print_tty(true);
+                                        table.as_display_table().print_tty(true)?;
                                         enable_raw_mode()?;
                                         String::new()
                                     }
@@ -469,7 +466,7 @@ fn main() -> BoxedRes<()> {
                                                 .ok(),
                                             (rlua::Value::String(k), rlua::Value::Integer(s)) => k
                                                 .to_str()
-                                                .and_then(|k| Ok((k, s.to_string())))
+                                                .map(|k| (k, s.to_string()))
                                                 .map(|(k, v)| {
                                                     prettytable::Row::new(vec![
                                                         prettytable::Cell::new(k),
@@ -493,7 +490,23 @@ fn main() -> BoxedRes<()> {
                                 .to_string(),
                                 _ => String::new(),
                             })
-                        }) {
+                        });
+                        clear_limits(&lua);
+
+                        if !code.trim().is_empty() {
+                            let entry = history::Entry {
+                                time,
+                                cwd,
+                                duration: start.elapsed(),
+                                success: res.is_ok(),
+                                code: code.clone(),
+                            };
+                            if let Err(e) = history::add(entry) {
+                                print(&format!("can't save history: {e}\n"))?;
+                            }
+                        }
+
+                        match res {
                             Ok(res) => {
                                 *should_tty.lock().unwrap() = false;
                                 print(&res)?;
@@ -502,7 +515,7 @@ fn main() -> BoxedRes<()> {
                             }
                             Err(e) => {
                                 *should_tty.lock().unwrap() = false;
-                                print(&e.to_string())?;
+                                print(&error_message(e.as_ref()))?;
                                 print("\n")?;
                                 cmd = Command::new_from(cmd, &lua);
                             }
@@ -514,26 +527,27 @@ fn main() -> BoxedRes<()> {
                     (KeyCode::Right, m) if m.is_empty() => {
                         cmd.right(false);
                     }
-                    (KeyCode::Up, m) if m.is_empty() => {
-                        cmd.up();
+                    (KeyCode::Up, m) if m.is_empty() && !cmd.up() => {
+                        cmd.history_prev();
                     }
-                    (KeyCode::Down, m) if m.is_empty() => {
-                        cmd.down();
+                    (KeyCode::Down, m) if m.is_empty() && !cmd.down() => {
+                        cmd.history_next();
                     }
+                    // `right` moves the cursor, which doesn't belong in a guard
+                    #[allow(clippy::collapsible_match)]
                     (KeyCode::Delete, m) if m.is_empty() => {
                         if cmd.right(true) {
                             cmd.remove_char()
                         }
                     }
-                    (KeyCode::Enter, m) if m.is_empty() => cmd.add_char('\n'),
-                    (KeyCode::Char(c), _) => cmd.add_char(if modifiers == KeyModifiers::SHIFT {
-                        c.to_uppercase().next().unwrap()
-                    } else {
-                        c
-                    }),
+                    (KeyCode::Char(c), m) if m.is_empty() || m == KeyModifiers::SHIFT => cmd
+                        .add_char(if modifiers == KeyModifiers::SHIFT {
+                            c.to_uppercase().next().unwrap()
+                        } else {
+                            c
+                        }),
                     _ => {}
-                },
-                _ => {}
+                }
             }
         }
     }
@@ -0,0 +1,1036 @@
+use std::sync::Mutex;
+
+use crossterm::event::{KeyCode, KeyModifiers};
+use tree_sitter::Parser;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use crate::editor::{
+    grapheme_at_column, is_word, next_grapheme, previous_grapheme, push_kill, Command,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Insert,
+    Normal,
+    Visual,
+}
+impl Mode {
+    fn name(&self) -> &'static str {
+        match self {
+            Mode::Insert => "insert",
+            Mode::Normal => "normal",
+            Mode::Visual => "visual",
+        }
+    }
+}
+
+/// Mode of the editor, `None` unless `config.edit_mode` is `"vi"`. Read by
+/// `vi_mode()`, for the prompt.
+static MODE: Mutex<Option<Mode>> = Mutex::new(None);
+
+pub fn mode_name() -> Option<&'static str> {
+    MODE.lock().unwrap().map(|mode| mode.name())
+}
+
+pub fn disable() {
+    *MODE.lock().unwrap() = None;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    /// `w`, or `W` when big: words are then everything between whitespace
+    NextWord(bool),
+    PreviousWord(bool),
+    WordEnd(bool),
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+    /// `gg`, or the line given as count
+    FirstLine,
+    /// `G`, or the line given as count
+    LastLine,
+    /// `f`, `F`, `t` and `T`
+    Find {
+        c: char,
+        forward: bool,
+        till: bool,
+    },
+}
+
+/// How much of the code between the cursor and the target of a motion an
+/// operator works on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Span {
+    /// Up to the target
+    Exclusive,
+    /// The target too
+    Inclusive,
+    /// Whole lines
+    Linewise,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Delete,
+    Change,
+    Yank,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    Motion(Motion),
+    /// `iw`, `a"`, `i(`... the text object in the code around the cursor
+    Object {
+        inner: bool,
+        c: char,
+    },
+    /// The operator typed twice, like `dd`
+    Line,
+    /// What's selected in visual mode
+    Selection,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Action {
+    Move(Motion),
+    Operate(Operator, Target),
+    /// `i`, `a`, `I`, `A`, `o` and `O`
+    Insert(char),
+    /// `r`
+    Replace(char),
+    /// Select a text object in visual mode
+    Select {
+        inner: bool,
+        c: char,
+    },
+    /// `p`, `P`, `u`, `v`, and `o` in visual mode
+    Key(char),
+}
+
+enum Parse<T> {
+    Done(T),
+    /// Could be the start of something
+    Incomplete,
+    Invalid,
+}
+
+/// Counts are capped so that `999999999999dd` can't hang the shell.
+const MAX_COUNT: usize = 10_000;
+
+/// Leading count of `keys`, and what follows it.
+fn count(keys: &str) -> (Option<usize>, &str) {
+    let digits = match keys.starts_with('0') {
+        true => 0,
+        false => keys
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(keys.len()),
+    };
+    let count = (digits > 0).then(|| {
+        keys[..digits]
+            .parse::<usize>()
+            .map_or(MAX_COUNT, |n| n.min(MAX_COUNT))
+    });
+    (count, &keys[digits..])
+}
+
+fn motion(keys: &str) -> Parse<Motion> {
+    let mut chars = keys.chars();
+    let motion = match chars.next() {
+        None => return Parse::Incomplete,
+        Some('h') => Motion::Left,
+        Some('l') | Some(' ') => Motion::Right,
+        Some('j') => Motion::Down,
+        Some('k') => Motion::Up,
+        Some('w') => Motion::NextWord(false),
+        Some('W') => Motion::NextWord(true),
+        Some('b') => Motion::PreviousWord(false),
+        Some('B') => Motion::PreviousWord(true),
+        Some('e') => Motion::WordEnd(false),
+        Some('E') => Motion::WordEnd(true),
+        Some('0') => Motion::LineStart,
+        Some('^') => Motion::FirstNonBlank,
+        Some('$') => Motion::LineEnd,
+        Some('G') => Motion::LastLine,
+        Some('g') => match chars.next() {
+            None => return Parse::Incomplete,
+            Some('g') => Motion::FirstLine,
+            Some(_) => return Parse::Invalid,
+        },
+        Some(f @ ('f' | 'F' | 't' | 'T')) => match chars.next() {
+            None => return Parse::Incomplete,
+            Some(c) => Motion::Find {
+                c,
+                forward: f.is_lowercase(),
+                till: f == 't' || f == 'T',
+            },
+        },
+        Some(_) => return Parse::Invalid,
+    };
+    match chars.next() {
+        None => Parse::Done(motion),
+        Some(_) => Parse::Invalid,
+    }
+}
+
+/// The text object named by `keys`, like `iw`.
+fn object(keys: &str) -> Parse<(bool, char)> {
+    let mut chars = keys.chars();
+    match (chars.next(), chars.next()) {
+        (Some(i), None) if i == 'i' || i == 'a' => Parse::Incomplete,
+        (Some(i), Some(c)) if i == 'i' || i == 'a' => Parse::Done((i == 'i', c)),
+        _ => Parse::Invalid,
+    }
+}
+
+/// The count and action typed as `keys`, like `2dw` or `ci"`.
+fn parse(keys: &str, visual: bool) -> Parse<(Option<usize>, Action)> {
+    let (count1, rest) = count(keys);
+    // Shorthands
+    let rest = match (visual, rest) {
+        (false, "x") => "dl",
+        (false, "X") => "dh",
+        (false, "D") => "d$",
+        (false, "C") => "c$",
+        (false, "s") => "cl",
+        (false, "S") => "cc",
+        (false, "Y") => "yy",
+        (true, "x") => "d",
+        (true, "s") => "c",
+        (_, rest) => rest,
+    };
+
+    let mut chars = rest.chars();
+    let action = match chars.next() {
+        None => return Parse::Incomplete,
+        Some(c @ ('d' | 'c' | 'y')) => {
+            let operator = match c {
+                'd' => Operator::Delete,
+                'c' => Operator::Change,
+                _ => Operator::Yank,
+            };
+            if visual {
+                return Parse::Done((count1, Action::Operate(operator, Target::Selection)));
+            }
+
+            let (count2, rest) = count(chars.as_str());
+            let count = match (count1, count2) {
+                (None, None) => None,
+                (a, b) => Some(a.unwrap_or(1).saturating_mul(b.unwrap_or(1)).min(MAX_COUNT)),
+            };
+            let target = match rest {
+                "" => return Parse::Incomplete,
+                rest if rest.starts_with(c) && rest.len() == 1 => Target::Line,
+                rest if rest.starts_with(['i', 'a']) => match object(rest) {
+                    Parse::Done((inner, c)) => Target::Object { inner, c },
+                    Parse::Incomplete => return Parse::Incomplete,
+                    Parse::Invalid => return Parse::Invalid,
+                },
+                rest => match motion(rest) {
+                    Parse::Done(motion) => Target::Motion(motion),
+                    Parse::Incomplete => return Parse::Incomplete,
+                    Parse::Invalid => return Parse::Invalid,
+                },
+            };
+            return Parse::Done((count, Action::Operate(operator, target)));
+        }
+        Some('i' | 'a') if visual => match object(rest) {
+            Parse::Done((inner, c)) => Action::Select { inner, c },
+            Parse::Incomplete => return Parse::Incomplete,
+            Parse::Invalid => return Parse::Invalid,
+        },
+        Some('o') if visual => Action::Key('o'),
+        Some(c @ ('i' | 'a' | 'I' | 'A' | 'o' | 'O')) => Action::Insert(c),
+        Some('r') => match chars.next() {
+            None => return Parse::Incomplete,
+            Some(c) => Action::Replace(c),
+        },
+        Some(c @ ('p' | 'P' | 'u' | 'v')) => Action::Key(c),
+        Some(_) => match motion(rest) {
+            Parse::Done(motion) => Action::Move(motion),
+            Parse::Incomplete => return Parse::Incomplete,
+            Parse::Invalid => return Parse::Invalid,
+        },
+    };
+    Parse::Done((count1, action))
+}
+
+fn line_start(code: &str, offset: usize) -> usize {
+    code[..offset].rfind('\n').map_or(0, |i| i + 1)
+}
+
+fn line_end(code: &str, offset: usize) -> usize {
+    code[offset..].find('\n').map_or(code.len(), |i| offset + i)
+}
+
+fn first_non_blank(code: &str, offset: usize) -> usize {
+    let start = line_start(code, offset);
+    let line = &code[start..line_end(code, offset)];
+    start + line.len() - line.trim_start().len()
+}
+
+/// Start of the line `n`, counting from 1, or of the last one.
+fn nth_line(code: &str, n: usize) -> usize {
+    code.match_indices('\n')
+        .nth(n.saturating_sub(2))
+        .filter(|_| n > 1)
+        .map_or(0, |(i, _)| i + 1)
+}
+
+/// Words are runs of graphemes of the same class.
+fn class(g: &str, big: bool) -> u8 {
+    match g.trim().is_empty() {
+        true => 0,
+        false if big || is_word(g) => 1,
+        false => 2,
+    }
+}
+
+/// Index of the grapheme at byte `offset` of the code.
+fn index(graphemes: &[(usize, &str)], offset: usize) -> usize {
+    graphemes.partition_point(|(i, _)| *i < offset)
+}
+
+fn next_word(graphemes: &[(usize, &str)], mut i: usize, big: bool) -> usize {
+    if let Some((_, g)) = graphemes.get(i) {
+        let c = class(g, big);
+        while i < graphemes.len() && c != 0 && class(graphemes[i].1, big) == c {
+            i += 1;
+        }
+    }
+    while i < graphemes.len() && class(graphemes[i].1, big) == 0 {
+        i += 1;
+    }
+    i
+}
+
+fn previous_word(graphemes: &[(usize, &str)], mut i: usize, big: bool) -> usize {
+    i = i.saturating_sub(1);
+    while i > 0 && class(graphemes[i].1, big) == 0 {
+        i -= 1;
+    }
+    let c = graphemes.get(i).map_or(0, |(_, g)| class(g, big));
+    while i > 0 && class(graphemes[i - 1].1, big) == c {
+        i -= 1;
+    }
+    i
+}
+
+/// Last grapheme of the word at `i`, or of the next one from whitespace.
+fn word_end(graphemes: &[(usize, &str)], mut i: usize, big: bool) -> usize {
+    while i < graphemes.len() && class(graphemes[i].1, big) == 0 {
+        i += 1;
+    }
+    let c = match graphemes.get(i) {
+        Some((_, g)) => class(g, big),
+        None => return graphemes.len().saturating_sub(1),
+    };
+    while i + 1 < graphemes.len() && class(graphemes[i + 1].1, big) == c {
+        i += 1;
+    }
+    i
+}
+
+/// Where `motion` goes from byte `offset` of `code`, if anywhere.
+fn destination(
+    code: &str,
+    offset: usize,
+    motion: Motion,
+    count: Option<usize>,
+) -> Option<(usize, Span)> {
+    let n = count.unwrap_or(1).min(MAX_COUNT);
+    let graphemes = code.grapheme_indices(true).collect::<Vec<_>>();
+    let byte = |i: usize| graphemes.get(i).map_or(code.len(), |(j, _)| *j);
+    let start = line_start(code, offset);
+    let end = line_end(code, offset);
+
+    let res = match motion {
+        Motion::Left => {
+            let mut res = offset;
+            for _ in 0..n {
+                res = start + previous_grapheme(&code[start..end], res - start);
+            }
+            (res, Span::Exclusive)
+        }
+        Motion::Right => {
+            let mut res = offset;
+            for _ in 0..n {
+                res = start + next_grapheme(&code[start..end], res - start);
+            }
+            (res, Span::Exclusive)
+        }
+        Motion::Up | Motion::Down => {
+            let column = code[start..offset].width();
+            let mut line = start;
+            for _ in 0..n {
+                line = match motion {
+                    Motion::Up if line > 0 => line_start(code, line - 1),
+                    Motion::Down if line_end(code, line) < code.len() => line_end(code, line) + 1,
+                    _ => return None,
+                };
+            }
+            let text = &code[line..line_end(code, line)];
+            (line + grapheme_at_column(text, column), Span::Linewise)
+        }
+        Motion::NextWord(big) => {
+            let mut i = index(&graphemes, offset);
+            for _ in 0..n {
+                i = next_word(&graphemes, i, big);
+            }
+            (byte(i), Span::Exclusive)
+        }
+        Motion::PreviousWord(big) => {
+            let mut i = index(&graphemes, offset);
+            for _ in 0..n {
+                i = previous_word(&graphemes, i, big);
+            }
+            (byte(i), Span::Exclusive)
+        }
+        Motion::WordEnd(big) => {
+            let mut i = index(&graphemes, offset);
+            for _ in 0..n {
+                i = word_end(&graphemes, i + 1, big);
+            }
+            (byte(i), Span::Inclusive)
+        }
+        Motion::LineStart => (start, Span::Exclusive),
+        Motion::FirstNonBlank => (first_non_blank(code, offset), Span::Exclusive),
+        Motion::LineEnd => (
+            start + previous_grapheme(&code[start..end], end - start),
+            Span::Inclusive,
+        ),
+        Motion::FirstLine => (first_non_blank(code, nth_line(code, n)), Span::Linewise),
+        Motion::LastLine => {
+            let line = match count {
+                Some(n) => nth_line(code, n),
+                None => line_start(code, code.len()),
+            };
+            (first_non_blank(code, line), Span::Linewise)
+        }
+        Motion::Find { c, forward, till } => {
+            let line = &code[start..end];
+            let column = offset - start;
+            let found = match forward {
+                true => {
+                    line.char_indices()
+                        .filter(|(i, x)| *x == c && *i > column)
+                        .nth(n - 1)?
+                        .0
+                }
+                false => {
+                    line[..column]
+                        .char_indices()
+                        .rev()
+                        .filter(|(_, x)| *x == c)
+                        .nth(n - 1)?
+                        .0
+                }
+            };
+            let res = match (till, forward) {
+                (false, _) => found,
+                (true, true) => previous_grapheme(line, found),
+                (true, false) => next_grapheme(line, found),
+            };
+            (start + res, Span::Inclusive)
+        }
+    };
+    Some(res)
+}
+
+/// Bytes `start..end` of the text object `c` around byte `offset`: a word,
+/// a string, what's between brackets or a function.
+fn text_object(code: &str, offset: usize, inner: bool, c: char) -> Option<(usize, usize)> {
+    match c {
+        'w' | 'W' => {
+            let graphemes = code.grapheme_indices(true).collect::<Vec<_>>();
+            let i = index(&graphemes, offset).min(graphemes.len().checked_sub(1)?);
+            let big = c == 'W';
+            let same = |j: usize| class(graphemes[j].1, big) == class(graphemes[i].1, big);
+            let blank = |j: usize| class(graphemes[j].1, big) == 0 && graphemes[j].1 != "\n";
+
+            let mut start = i;
+            while start > 0 && same(start - 1) {
+                start -= 1;
+            }
+            let mut end = i + 1;
+            while end < graphemes.len() && same(end) {
+                end += 1;
+            }
+            if !inner {
+                if end < graphemes.len() && blank(end) {
+                    while end < graphemes.len() && blank(end) {
+                        end += 1;
+                    }
+                } else {
+                    while start > 0 && blank(start - 1) {
+                        start -= 1;
+                    }
+                }
+            }
+
+            let byte = |i: usize| graphemes.get(i).map_or(code.len(), |(j, _)| *j);
+            Some((byte(start), byte(end)))
+        }
+        // Any kind of string, long strings included
+        '"' | '\'' | '`' => delimited(code, offset, inner, "string_start", "string_end"),
+        '(' | ')' | 'b' => delimited(code, offset, inner, "(", ")"),
+        '{' | '}' | 'B' => delimited(code, offset, inner, "{", "}"),
+        '[' | ']' => delimited(code, offset, inner, "[", "]"),
+        'f' => {
+            let tree = parse_lua(code)?;
+            let mut node = tree.root_node().descendant_for_byte_range(offset, offset);
+            while let Some(n) = node {
+                if let "function_declaration" | "function_definition" = n.kind() {
+                    if !inner {
+                        return Some((n.start_byte(), n.end_byte()));
+                    }
+                    let mut cursor = n.walk();
+                    let body = n.children(&mut cursor).find(|c| c.kind() == "block");
+                    return body.map(|body| (body.start_byte(), body.end_byte()));
+                }
+                node = n.parent();
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+fn parse_lua(code: &str) -> Option<tree_sitter::Tree> {
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_lua::language()).ok()?;
+    parser.parse(code, None)
+}
+
+/// The innermost `open` and `close` tokens of the same node around byte
+/// `offset`, and what's between them.
+fn delimited(
+    code: &str,
+    offset: usize,
+    inner: bool,
+    open: &str,
+    close: &str,
+) -> Option<(usize, usize)> {
+    let tree = parse_lua(code)?;
+    let mut node = tree.root_node().descendant_for_byte_range(offset, offset);
+
+    while let Some(n) = node {
+        let mut cursor = n.walk();
+        let children = n.children(&mut cursor).collect::<Vec<_>>();
+        let opening = children.iter().position(|c| c.kind() == open);
+        let closing = opening.and_then(|o| {
+            children[o + 1..]
+                .iter()
+                .find(|c| c.kind() == close)
+                .map(|c| (children[o], *c))
+        });
+        if let Some((o, c)) = closing {
+            if o.start_byte() <= offset && offset < c.end_byte() {
+                return Some(match inner {
+                    true => (o.end_byte(), c.start_byte()),
+                    false => (o.start_byte(), c.end_byte()),
+                });
+            }
+        }
+        node = n.parent();
+    }
+    None
+}
+
+/// Modal editing of the buffer, when `config.edit_mode` is `"vi"`.
+pub struct Vi {
+    mode: Mode,
+    /// Keys of the command being typed, like `2d` or `ci`
+    pending: String,
+    /// Where the selection started in visual mode, as a byte offset
+    visual_start: usize,
+    /// What was last deleted or yanked, and whether it's whole lines
+    register: Option<(String, bool)>,
+    /// Code and cursor offset before each change
+    undo: Vec<(String, usize)>,
+}
+impl Vi {
+    pub fn new() -> Self {
+        *MODE.lock().unwrap() = Some(Mode::Insert);
+        Vi {
+            mode: Mode::Insert,
+            pending: String::new(),
+            visual_start: 0,
+            register: None,
+            undo: vec![],
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        *MODE.lock().unwrap() = Some(mode);
+    }
+
+    /// Bytes of the code selected in visual mode.
+    pub fn selection(&self, code: &str, offset: usize) -> Option<(usize, usize)> {
+        if self.mode != Mode::Visual {
+            return None;
+        }
+        // The code may have changed under `visual_start`, like when going
+        // through the history.
+        let clamp = |mut i: usize| {
+            i = i.min(code.len());
+            while !code.is_char_boundary(i) {
+                i -= 1;
+            }
+            i
+        };
+        let start = clamp(self.visual_start.min(offset));
+        let last = clamp(self.visual_start.max(offset));
+        Some((start, next_grapheme(code, last)))
+    }
+
+    /// Handle a key outside of insert mode, or the Esc leaving it. Keys vi
+    /// doesn't use, like Enter or Ctrl-C, are left to the editor, returning
+    /// `false`.
+    pub fn key(&mut self, cmd: &mut Command, code: KeyCode, modifiers: KeyModifiers) -> bool {
+        if self.mode == Mode::Insert {
+            if code != KeyCode::Esc {
+                return false;
+            }
+            self.set_mode(Mode::Normal);
+            cmd.left();
+            return true;
+        }
+
+        let c = match (code, modifiers) {
+            (KeyCode::Char(c), KeyModifiers::SHIFT) => c.to_uppercase().next().unwrap(),
+            (KeyCode::Char(c), m) if m.is_empty() => c,
+            (KeyCode::Backspace, m) if m.is_empty() => 'h',
+            (KeyCode::Esc, _) => {
+                self.pending.clear();
+                self.set_mode(Mode::Normal);
+                return true;
+            }
+            _ => {
+                self.pending.clear();
+                return false;
+            }
+        };
+
+        self.pending.push(c);
+        match parse(&self.pending, self.mode == Mode::Visual) {
+            Parse::Incomplete => {}
+            Parse::Invalid => self.pending.clear(),
+            Parse::Done((count, action)) => {
+                self.pending.clear();
+                self.run(cmd, count, action);
+            }
+        }
+
+        // The cursor stays on a character outside of insert mode
+        let (code, offset) = (cmd.code(), cmd.offset());
+        if self.mode != Mode::Insert
+            && offset == line_end(&code, offset)
+            && offset > line_start(&code, offset)
+        {
+            cmd.set_offset(previous_grapheme(&code, offset));
+        }
+        true
+    }
+
+    fn snapshot(&mut self, cmd: &Command) {
+        self.undo.push((cmd.code(), cmd.offset()));
+    }
+
+    fn run(&mut self, cmd: &mut Command, count: Option<usize>, action: Action) {
+        let code = cmd.code();
+        let offset = cmd.offset();
+
+        match action {
+            Action::Move(motion) => match destination(&code, offset, motion, count) {
+                Some((to, _)) => cmd.set_offset(to),
+                // Like the arrows, going past the buffer walks the history
+                None if motion == Motion::Up => {
+                    cmd.history_prev();
+                }
+                None if motion == Motion::Down => {
+                    cmd.history_next();
+                }
+                None => {}
+            },
+            Action::Operate(operator, target) => {
+                if let Some((start, end, linewise)) =
+                    self.range(&code, offset, operator, count, target)
+                {
+                    self.operate(cmd, operator, start, end, linewise);
+                }
+            }
+            Action::Insert(c) => {
+                self.snapshot(cmd);
+                match c {
+                    'a' => {
+                        cmd.right(false);
+                    }
+                    'I' => cmd.set_offset(first_non_blank(&code, offset)),
+                    'A' => cmd.line_end(),
+                    'o' => {
+                        cmd.line_end();
+                        cmd.newline();
+                    }
+                    'O' => {
+                        let indent =
+                            &code[line_start(&code, offset)..first_non_blank(&code, offset)];
+                        cmd.line_start();
+                        cmd.insert(&format!("{indent}\n"));
+                        cmd.up();
+                        cmd.line_end();
+                    }
+                    _ => {}
+                }
+                self.set_mode(Mode::Insert);
+            }
+            Action::Replace(c) => {
+                let n = count.unwrap_or(1).min(MAX_COUNT);
+                let end = match destination(&code, offset, Motion::Right, Some(n)) {
+                    Some((end, _)) if code[offset..end].graphemes(true).count() == n => end,
+                    _ => return,
+                };
+                self.snapshot(cmd);
+                let mut code = code;
+                code.replace_range(offset..end, &c.to_string().repeat(n));
+                cmd.set_code(&code);
+                cmd.set_offset(offset + c.len_utf8() * (n - 1));
+            }
+            Action::Select { inner, c } => {
+                if let Some((start, end)) = text_object(&code, offset, inner, c) {
+                    self.visual_start = start;
+                    cmd.set_offset(previous_grapheme(&code, end).max(start));
+                }
+            }
+            Action::Key('p' | 'P') => {
+                let (text, linewise) = match &self.register {
+                    Some(register) => register.clone(),
+                    None => return,
+                };
+                self.snapshot(cmd);
+                let text = text.repeat(count.unwrap_or(1).min(1000));
+                match (linewise, action) {
+                    (true, Action::Key('p')) => {
+                        cmd.set_offset(line_end(&code, offset));
+                        cmd.insert(&format!("\n{text}"));
+                        cmd.set_offset(line_end(&code, offset) + 1);
+                    }
+                    (true, _) => {
+                        cmd.set_offset(line_start(&code, offset));
+                        cmd.insert(&format!("{text}\n"));
+                        cmd.set_offset(line_start(&code, offset));
+                    }
+                    (false, Action::Key('p')) => {
+                        cmd.right(false);
+                        cmd.insert(&text);
+                        cmd.left();
+                    }
+                    (false, _) => {
+                        cmd.insert(&text);
+                        cmd.left();
+                    }
+                }
+            }
+            Action::Key('u') => {
+                if let Some((code, offset)) = self.undo.pop() {
+                    cmd.set_code(&code);
+                    cmd.set_offset(offset);
+                    self.set_mode(Mode::Normal);
+                }
+            }
+            Action::Key('v') => match self.mode {
+                Mode::Visual => self.set_mode(Mode::Normal),
+                _ => {
+                    self.visual_start = offset;
+                    self.set_mode(Mode::Visual);
+                }
+            },
+            Action::Key('o') => {
+                cmd.set_offset(self.visual_start);
+                self.visual_start = offset;
+            }
+            Action::Key(_) => {}
+        }
+    }
+
+    /// Bytes `start..end` of the code `operator` works on, and whether it's
+    /// whole lines.
+    fn range(
+        &self,
+        code: &str,
+        offset: usize,
+        operator: Operator,
+        count: Option<usize>,
+        target: Target,
+    ) -> Option<(usize, usize, bool)> {
+        let (start, end, span) = match target {
+            Target::Motion(motion) => {
+                let (to, span) = match motion {
+                    // `cw` changes the word, not what follows
+                    Motion::NextWord(big)
+                        if operator == Operator::Change
+                            && !code[offset..].starts_with(char::is_whitespace) =>
+                    {
+                        let graphemes = code.grapheme_indices(true).collect::<Vec<_>>();
+                        let mut i = word_end(&graphemes, index(&graphemes, offset), big);
+                        for _ in 1..count.unwrap_or(1).min(MAX_COUNT) {
+                            i = word_end(&graphemes, i + 1, big);
+                        }
+                        (
+                            graphemes.get(i).map_or(code.len(), |(j, _)| *j),
+                            Span::Inclusive,
+                        )
+                    }
+                    // `dw` on the last word of a line stops at its end
+                    Motion::NextWord(_) => {
+                        let (to, span) = destination(code, offset, motion, count)?;
+                        (to.min(line_end(code, offset)).max(offset), span)
+                    }
+                    _ => destination(code, offset, motion, count)?,
+                };
+                (offset.min(to), offset.max(to), span)
+            }
+            Target::Object { inner, c } => {
+                let (start, end) = text_object(code, offset, inner, c)?;
+                (start, end, Span::Exclusive)
+            }
+            Target::Line => {
+                let mut end = offset;
+                for _ in 1..count.unwrap_or(1).min(MAX_COUNT) {
+                    end = line_end(code, end);
+                    if end < code.len() {
+                        end += 1;
+                    }
+                }
+                (offset, end, Span::Linewise)
+            }
+            Target::Selection => {
+                let (start, end) = self.selection(code, offset)?;
+                (start, end, Span::Exclusive)
+            }
+        };
+
+        Some(match span {
+            Span::Exclusive => (start, end, false),
+            Span::Inclusive if end == line_end(code, end) => (start, end, false),
+            Span::Inclusive => (start, next_grapheme(code, end), false),
+            Span::Linewise => (line_start(code, start), line_end(code, end), true),
+        })
+    }
+
+    fn operate(
+        &mut self,
+        cmd: &mut Command,
+        operator: Operator,
+        start: usize,
+        end: usize,
+        linewise: bool,
+    ) {
+        let code = cmd.code();
+        let text = code[start..end].to_string();
+        self.register = Some((text.clone(), linewise));
+        if operator != Operator::Yank && start != end {
+            self.snapshot(cmd);
+        }
+
+        match operator {
+            Operator::Yank => {
+                push_kill(text);
+                cmd.set_offset(start);
+                self.set_mode(Mode::Normal);
+            }
+            // Whole lines go with a line break, the one before for the last line
+            Operator::Delete if linewise => {
+                match (end < code.len(), start > 0) {
+                    (true, _) => cmd.kill(start, end + 1),
+                    (false, true) => cmd.kill(start - 1, end),
+                    (false, false) => cmd.kill(start, end),
+                }
+                let code = cmd.code();
+                cmd.set_offset(first_non_blank(&code, cmd.offset().min(code.len())));
+                self.set_mode(Mode::Normal);
+            }
+            Operator::Delete => {
+                cmd.kill(start, end);
+                self.set_mode(Mode::Normal);
+            }
+            // The indentation of changed lines is kept
+            Operator::Change => {
+                let start = match linewise {
+                    true => first_non_blank(&code, start),
+                    false => start,
+                };
+                cmd.kill(start, end.max(start));
+                cmd.set_offset(start);
+                self.set_mode(Mode::Insert);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts() {
+        assert_eq!(count("12dw"), (Some(12), "dw"));
+        assert_eq!(count("0"), (None, "0"));
+        assert_eq!(count("dw"), (None, "dw"));
+        assert_eq!(count("99999999999999999999x"), (Some(MAX_COUNT), "x"));
+
+        assert!(matches!(parse("2d3w", false), Parse::Done((Some(6), _))));
+        assert!(matches!(parse("d3w", false), Parse::Done((Some(3), _))));
+        assert!(matches!(parse("dw", false), Parse::Done((None, _))));
+        assert!(matches!(
+            parse("999999999999d999999999999d", false),
+            Parse::Done((
+                Some(MAX_COUNT),
+                Action::Operate(Operator::Delete, Target::Line)
+            ))
+        ));
+    }
+
+    #[test]
+    fn parsing() {
+        assert!(matches!(parse("", false), Parse::Incomplete));
+        assert!(matches!(parse("2", false), Parse::Incomplete));
+        assert!(matches!(parse("d", false), Parse::Incomplete));
+        assert!(matches!(parse("ci", false), Parse::Incomplete));
+        assert!(matches!(parse("f", false), Parse::Incomplete));
+        assert!(matches!(parse("g", false), Parse::Incomplete));
+        assert!(matches!(parse("dq", false), Parse::Invalid));
+
+        assert!(matches!(
+            parse("gg", false),
+            Parse::Done((None, Action::Move(Motion::FirstLine)))
+        ));
+        assert!(matches!(
+            parse("3W", false),
+            Parse::Done((Some(3), Action::Move(Motion::NextWord(true))))
+        ));
+        assert!(matches!(
+            parse("dtx", false),
+            Parse::Done((
+                None,
+                Action::Operate(
+                    Operator::Delete,
+                    Target::Motion(Motion::Find {
+                        c: 'x',
+                        forward: true,
+                        till: true
+                    })
+                )
+            ))
+        ));
+        assert!(matches!(
+            parse("ci\"", false),
+            Parse::Done((
+                None,
+                Action::Operate(
+                    Operator::Change,
+                    Target::Object {
+                        inner: true,
+                        c: '"'
+                    }
+                )
+            ))
+        ));
+        assert!(matches!(
+            parse("x", false),
+            Parse::Done((
+                None,
+                Action::Operate(Operator::Delete, Target::Motion(Motion::Right))
+            ))
+        ));
+        assert!(matches!(
+            parse("x", true),
+            Parse::Done((None, Action::Operate(Operator::Delete, Target::Selection)))
+        ));
+        assert!(matches!(
+            parse("aw", true),
+            Parse::Done((
+                None,
+                Action::Select {
+                    inner: false,
+                    c: 'w'
+                }
+            ))
+        ));
+        assert!(matches!(
+            parse("aw", false),
+            Parse::Done((None, Action::Insert('a')))
+        ));
+        assert!(matches!(
+            parse("ré", false),
+            Parse::Done((None, Action::Replace('é')))
+        ));
+    }
+
+    #[test]
+    fn motions() {
+        let code = "local x = f(a, b)\n  return x";
+        let to = |offset, motion, count| destination(code, offset, motion, count).map(|d| d.0);
+        assert_eq!(to(0, Motion::NextWord(false), None), Some(6));
+        assert_eq!(to(0, Motion::NextWord(false), Some(4)), Some(11));
+        assert_eq!(to(0, Motion::NextWord(true), Some(3)), Some(10));
+        assert_eq!(to(6, Motion::PreviousWord(false), None), Some(0));
+        assert_eq!(to(0, Motion::WordEnd(false), None), Some(4));
+        assert_eq!(to(0, Motion::LineEnd, None), Some(16));
+        assert_eq!(to(22, Motion::FirstNonBlank, None), Some(20));
+        assert_eq!(to(22, Motion::LineStart, None), Some(18));
+        assert_eq!(to(2, Motion::Down, None), Some(20));
+        assert_eq!(to(0, Motion::LastLine, None), Some(20));
+        assert_eq!(
+            to(
+                0,
+                Motion::Find {
+                    c: 'b',
+                    forward: true,
+                    till: false
+                },
+                None
+            ),
+            Some(15)
+        );
+        assert_eq!(
+            to(
+                0,
+                Motion::Find {
+                    c: 'b',
+                    forward: true,
+                    till: true
+                },
+                None
+            ),
+            Some(14)
+        );
+        assert_eq!(to(0, Motion::Right, Some(usize::MAX)), Some(17));
+    }
+
+    #[test]
+    fn text_objects() {
+        let code = "print(\"hello world\", {1, 2})";
+        assert_eq!(text_object(code, 9, true, 'w'), Some((7, 12)));
+        assert_eq!(text_object(code, 9, false, 'w'), Some((7, 13)));
+        assert_eq!(text_object(code, 9, true, '"'), Some((7, 18)));
+        assert_eq!(text_object(code, 9, false, '"'), Some((6, 19)));
+        assert_eq!(text_object(code, 9, true, '('), Some((6, 27)));
+        assert_eq!(text_object(code, 23, true, '{'), Some((22, 26)));
+        assert_eq!(text_object(code, 23, false, 'B'), Some((21, 27)));
+        assert_eq!(text_object(code, 9, true, '['), None);
+
+        let code = "f = function(a) return a end";
+        assert_eq!(text_object(code, 17, true, 'f'), Some((16, 24)));
+        assert_eq!(text_object(code, 17, false, 'f'), Some((4, 28)));
+    }
+}